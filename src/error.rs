@@ -10,11 +10,13 @@ pub enum Error {
 
     // -- pg
     TableExists(String),
+    IncompatibleSchema(String),
 
     // -- format
     UnsupportedFileExtension(String),
     UnsupportedShapeType(String),
     MixedDataTypes(String),
+    ReaderPanicked(String),
 
     // -- Externals
     #[from]
@@ -29,10 +31,26 @@ pub enum Error {
     ProjTransform(proj::ProjError),
     #[from]
     OsmPbf(osmpbf::Error),
+    #[from]
+    ThreadPool(rayon::ThreadPoolBuildError),
+    #[from]
+    Xml(quick_xml::Error),
+    #[from]
+    Parquet(parquet::errors::ParquetError),
+    #[from]
+    Arrow(arrow_schema::ArrowError),
+    GeoJson(Box<geojson::Error>),
 }
 
 // region:    --- Error Boilerplate
 
+// geojson::Error is large, box it to keep Result small
+impl From<geojson::Error> for Error {
+    fn from(val: geojson::Error) -> Self {
+        Self::GeoJson(Box::new(val))
+    }
+}
+
 impl core::fmt::Display for Error {
     fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::result::Result<(), core::fmt::Error> {
         write!(fmt, "{self:?}")
@@ -1,12 +1,20 @@
 use crate::{Error, Result};
 
-use postgres::types::Type;
+use postgres::types::{Kind, Type};
+use rayon::prelude::*;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
 
 use crate::pg::binary_copy::Wkb;
 
 // Struct to hold column name and data type
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NewTableTypes {
     pub column_name: String,
     pub data_type: Type,
@@ -17,10 +25,11 @@ pub struct Row {
     pub columns: Vec<AcceptedTypes>,
 }
 
-#[derive(Debug)]
-pub struct Rows {
-    pub row: Vec<Row>,
-}
+// Number of rows buffered between a reader and the binary copy writer
+const ROW_BUFFER_SIZE: usize = 1024;
+
+// Rows streamed from a reader, in file order
+pub type RowStream = Receiver<Result<Row>>;
 
 impl Row {
     pub fn new() -> Self {
@@ -31,35 +40,437 @@ impl Row {
     pub fn add(&mut self, column: AcceptedTypes) {
         self.columns.push(column);
     }
+    // Keep only the values at the given positions, in that order
+    pub fn select(self, indices: &[usize]) -> Self {
+        let mut columns: Vec<Option<AcceptedTypes>> = self.columns.into_iter().map(Some).collect();
+        Row {
+            columns: indices
+                .iter()
+                .filter_map(|index| columns.get_mut(*index).and_then(Option::take))
+                .collect(),
+        }
+    }
 }
 
-impl Rows {
-    pub fn new() -> Self {
-        Rows { row: Vec::new() }
-    }
-    pub fn add(&mut self, row: Row) {
-        self.row.push(row);
+// Run a reader on its own thread and stream its rows through a bounded channel,
+// so memory stays flat regardless of the input size
+pub fn stream_rows<F, T>(read: F) -> Receiver<Result<T>>
+where
+    F: FnOnce(&SyncSender<Result<T>>) -> Result<()> + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = sync_channel(ROW_BUFFER_SIZE);
+    thread::spawn(move || {
+        // A panic would drop the sender and end the stream as if the input was complete,
+        // so it is sent on as an error that aborts the load
+        let result = panic::catch_unwind(AssertUnwindSafe(|| read(&sender)))
+            .unwrap_or_else(|payload| Err(Error::ReaderPanicked(panic_message(payload))));
+        if let Err(err) = result {
+            // Fails only if the writer is gone, in which case nobody is listening
+            let _ = sender.send(Err(err));
+        }
+    });
+    receiver
+}
+
+// Features handed to the pool of threads at once, rows are sent in file order after each batch
+const CONVERT_BATCH_SIZE: usize = 4096;
+
+// Finish rows on a pool of threads, all cores unless told otherwise. Each thread gets its
+// own state from `init`, e.g. a PROJ transformer, which can't be shared between threads.
+// A thread that can't build its state fails the items handed to it
+pub fn convert_in_parallel<T, S, I, F>(
+    items: impl Iterator<Item = Result<T>>,
+    threads: Option<usize>,
+    sender: &SyncSender<Result<Row>>,
+    init: I,
+    convert: F,
+) -> Result<()>
+where
+    T: Send,
+    I: Fn() -> Result<S> + Sync + Send,
+    F: Fn(&mut S, T) -> Result<Row> + Sync + Send,
+{
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.unwrap_or(0))
+        .build()?;
+    // Some readers panic when polled again after their last item
+    let mut items = items.fuse().peekable();
+    while items.peek().is_some() {
+        let batch = items
+            .by_ref()
+            .take(CONVERT_BATCH_SIZE)
+            .collect::<Result<Vec<T>>>()?;
+        let rows: Vec<Result<Row>> = pool.install(|| {
+            batch
+                .into_par_iter()
+                .map_init(
+                    || None,
+                    |state, item| {
+                        let state = match state {
+                            Some(state) => state,
+                            None => state.insert(init()?),
+                        };
+                        convert(state, item)
+                    },
+                )
+                .collect()
+        });
+        for row in rows {
+            // Writer has gone away, stop reading
+            if sender.send(row).is_err() {
+                return Ok(());
+            }
+        }
     }
+    Ok(())
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    let message = match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload
+            .downcast_ref::<&str>()
+            .map_or("unknown error".to_string(), |message| message.to_string()),
+    };
+    format!("❌ Reading the input failed: {}", message)
 }
 
 // Enum to hold accepted data types
 #[derive(Debug)]
 pub enum AcceptedTypes {
     Int(Option<i32>),
+    BigInt(Option<i64>),
     Float(Option<f64>),
     Double(Option<f32>),
     Text(Option<String>),
     Bool(Option<bool>),
     Array(Option<Vec<String>>),
+    Json(Option<serde_json::Value>),
+    Hstore(Option<HashMap<String, Option<String>>>),
     Geometry(Option<Wkb>),
 }
 
+// The hstore extension doesn't have a fixed oid, the actual type is read from the table
+pub fn hstore_type() -> Type {
+    Type::new("hstore".into(), 0, Kind::Simple, "public".into())
+}
+
+impl AcceptedTypes {
+    // A NULL of the variant written into a column of the given type
+    pub fn null(data_type: &Type) -> Self {
+        match *data_type {
+            Type::INT4 => AcceptedTypes::Int(None),
+            Type::INT8 => AcceptedTypes::BigInt(None),
+            Type::FLOAT8 => AcceptedTypes::Float(None),
+            Type::BOOL => AcceptedTypes::Bool(None),
+            Type::TEXT_ARRAY => AcceptedTypes::Array(None),
+            Type::JSONB => AcceptedTypes::Json(None),
+            _ if data_type.name() == "hstore" => AcceptedTypes::Hstore(None),
+            _ => AcceptedTypes::Text(None),
+        }
+    }
+
+    // Convert a value so it can be written into a column of the given type,
+    // values that can't be converted are returned as they are
+    pub fn coerce(self, data_type: &Type) -> Self {
+        match (self, data_type) {
+            (AcceptedTypes::Int(value), &Type::INT8) => AcceptedTypes::BigInt(value.map(i64::from)),
+            (AcceptedTypes::Int(value), &Type::FLOAT8) => {
+                AcceptedTypes::Float(value.map(f64::from))
+            }
+            (AcceptedTypes::BigInt(value), &Type::FLOAT8) => {
+                AcceptedTypes::Float(value.map(|value| value as f64))
+            }
+            (AcceptedTypes::Double(value), &Type::FLOAT8) => {
+                AcceptedTypes::Float(value.map(f64::from))
+            }
+            (AcceptedTypes::Int(value), &Type::TEXT) => {
+                AcceptedTypes::Text(value.map(|value| value.to_string()))
+            }
+            (AcceptedTypes::BigInt(value), &Type::TEXT) => {
+                AcceptedTypes::Text(value.map(|value| value.to_string()))
+            }
+            (AcceptedTypes::Float(value), &Type::TEXT) => {
+                AcceptedTypes::Text(value.map(|value| value.to_string()))
+            }
+            (AcceptedTypes::Double(value), &Type::TEXT) => {
+                AcceptedTypes::Text(value.map(|value| value.to_string()))
+            }
+            (AcceptedTypes::Bool(value), &Type::TEXT) => {
+                AcceptedTypes::Text(value.map(|value| value.to_string()))
+            }
+            (value, _) => value,
+        }
+    }
+}
+
+// Report properties that are not part of the schema, once per name
+pub fn report_extra_columns<'a, I>(column_names: I, reported: &mut HashSet<String>)
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    for column_name in column_names {
+        if reported.insert(column_name.clone()) {
+            println!("❌ Property '{}' not in schema, skipped", column_name);
+        }
+    }
+}
+
+// What to do with a column holding values of types that can't be widened into one another
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum OnMixedTypes {
+    #[default]
+    Text,
+    Error,
+}
+
+// Type of a column holding values of both types. Integers widen to floats, anything
+// else that differs is mixed
+pub fn widen_type(
+    column_name: &str,
+    current: &Type,
+    new: &Type,
+    on_mixed_types: OnMixedTypes,
+) -> Result<Type> {
+    match (current, new) {
+        _ if current == new => Ok(current.clone()),
+        (&Type::INT8, &Type::FLOAT8) | (&Type::FLOAT8, &Type::INT8) => Ok(Type::FLOAT8),
+        _ => match on_mixed_types {
+            OnMixedTypes::Text => Ok(Type::TEXT),
+            OnMixedTypes::Error => Err(Error::MixedDataTypes(format!(
+                "❌ Column '{}' contains mixed data types: {} and {}",
+                column_name,
+                current.name(),
+                new.name()
+            ))),
+        },
+    }
+}
+
+// Columns found while scanning features, in the order they first appear
+pub struct SchemaInference {
+    columns: Vec<(String, Option<Type>)>,
+    positions: HashMap<String, usize>,
+    on_mixed_types: OnMixedTypes,
+}
+
+impl SchemaInference {
+    pub fn new(on_mixed_types: OnMixedTypes) -> Self {
+        SchemaInference {
+            columns: Vec::new(),
+            positions: HashMap::new(),
+            on_mixed_types,
+        }
+    }
+    // A value without a type, i.e. null, still creates the column
+    pub fn add(&mut self, column_name: &str, data_type: Option<Type>) -> Result<()> {
+        let Some(&position) = self.positions.get(column_name) else {
+            self.positions
+                .insert(column_name.to_string(), self.columns.len());
+            self.columns.push((column_name.to_string(), data_type));
+            return Ok(());
+        };
+        let (_, current) = &mut self.columns[position];
+        *current = match (current.take(), data_type) {
+            (Some(current), Some(new)) => Some(widen_type(
+                column_name,
+                &current,
+                &new,
+                self.on_mixed_types,
+            )?),
+            (current, new) => current.or(new),
+        };
+        Ok(())
+    }
+    // Columns that only ever held nulls are created as text
+    pub fn finish(self) -> Vec<NewTableTypes> {
+        self.columns
+            .into_iter()
+            .map(|(column_name, data_type)| NewTableTypes {
+                column_name,
+                data_type: data_type.unwrap_or(Type::TEXT),
+            })
+            .collect()
+    }
+}
+
+pub fn print_schema(config: &[NewTableTypes]) {
+    println!("Schema:");
+    for column in config.iter() {
+        println!("  {}: {}", column.column_name, column.data_type.name());
+    }
+    println!("  geom: geometry");
+}
+
+// Whether values of a column can be written into a column of another type
+pub fn can_coerce(from: &Type, to: &Type) -> bool {
+    from == to
+        || from.name() == to.name()
+        || matches!(
+            (from, to),
+            (&Type::INT4, &Type::INT8)
+                | (&Type::INT4, &Type::FLOAT8)
+                | (&Type::INT8, &Type::FLOAT8)
+                | (&Type::INT4, &Type::TEXT)
+                | (&Type::INT8, &Type::TEXT)
+                | (&Type::FLOAT8, &Type::TEXT)
+                | (&Type::BOOL, &Type::TEXT)
+                | (&Type::TEXT, &Type::VARCHAR)
+        )
+}
+
+// Postgres truncates identifiers longer than this
+const MAX_IDENTIFIER_LENGTH: usize = 63;
+
+// Keywords Postgres reserves, which can't be used as column names without quoting
+const RESERVED_WORDS: &[&str] = &[
+    "all",
+    "analyse",
+    "analyze",
+    "and",
+    "any",
+    "array",
+    "as",
+    "asc",
+    "asymmetric",
+    "both",
+    "case",
+    "cast",
+    "check",
+    "collate",
+    "column",
+    "constraint",
+    "create",
+    "current_catalog",
+    "current_date",
+    "current_role",
+    "current_time",
+    "current_timestamp",
+    "current_user",
+    "default",
+    "deferrable",
+    "desc",
+    "distinct",
+    "do",
+    "else",
+    "end",
+    "except",
+    "false",
+    "fetch",
+    "for",
+    "foreign",
+    "from",
+    "grant",
+    "group",
+    "having",
+    "in",
+    "initially",
+    "intersect",
+    "into",
+    "lateral",
+    "leading",
+    "limit",
+    "localtime",
+    "localtimestamp",
+    "not",
+    "null",
+    "offset",
+    "on",
+    "only",
+    "or",
+    "order",
+    "placing",
+    "primary",
+    "references",
+    "returning",
+    "select",
+    "session_user",
+    "some",
+    "symmetric",
+    "system_user",
+    "table",
+    "then",
+    "to",
+    "trailing",
+    "true",
+    "union",
+    "unique",
+    "user",
+    "using",
+    "variadic",
+    "when",
+    "where",
+    "window",
+    "with",
+];
+
+// Lowercase a name, strip accents and turn anything else that would need quoting into '_'.
+// Reserved words get a trailing '_'
+fn sanitize_name(name: &str) -> String {
+    let mut sanitized = String::new();
+    for c in name.nfkd().filter(|c| !is_combining_mark(*c)) {
+        if c.is_ascii_alphanumeric() {
+            sanitized.push(c.to_ascii_lowercase());
+        } else if !sanitized.ends_with('_') {
+            sanitized.push('_');
+        }
+    }
+    let mut sanitized = sanitized.trim_matches('_').to_string();
+    if sanitized.is_empty() {
+        sanitized = "column".into();
+    }
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized.truncate(MAX_IDENTIFIER_LENGTH);
+    if RESERVED_WORDS.contains(&sanitized.as_str()) {
+        sanitized.push('_');
+    }
+    sanitized
+}
+
+// Rename columns so they can be used in SQL without quoting. Names that end up the same
+// get a numeric suffix
+pub fn sanitize_column_names(config: Vec<NewTableTypes>) -> Vec<NewTableTypes> {
+    // The geometry column is always called geom
+    let mut taken: HashSet<String> = HashSet::from(["geom".to_string()]);
+    config
+        .into_iter()
+        .map(|column| {
+            let base = sanitize_name(&column.column_name);
+            let mut column_name = base.clone();
+            let mut suffix = 1;
+            while taken.contains(&column_name) {
+                let suffix_str = format!("_{}", suffix);
+                let length = base.len().min(MAX_IDENTIFIER_LENGTH - suffix_str.len());
+                column_name = format!("{}{}", &base[..length], suffix_str);
+                suffix += 1;
+            }
+            taken.insert(column_name.clone());
+            if column_name != column.column_name {
+                println!(
+                    "Column '{}' renamed to '{}'",
+                    column.column_name, column_name
+                );
+            }
+            NewTableTypes {
+                column_name,
+                data_type: column.data_type,
+            }
+        })
+        .collect()
+}
+
 // Create enum of supported file types
 #[derive(Debug, PartialEq)]
 pub enum FileType {
     Shapefile,
     GeoJson,
     Osmpbf,
+    GeoParquet,
+    // Changes to apply to an earlier OsmPbf import
+    OsmChange,
 }
 
 pub fn determine_file_type(input_file: &str) -> Result<FileType> {
@@ -73,6 +484,9 @@ pub fn determine_file_type(input_file: &str) -> Result<FileType> {
         "shp" => Ok(FileType::Shapefile),
         "geojson" => Ok(FileType::GeoJson),
         "pbf" => Ok(FileType::Osmpbf),
+        "osc" => Ok(FileType::OsmChange),
+        "gz" if input_file.ends_with(".osc.gz") => Ok(FileType::OsmChange),
+        "parquet" | "geoparquet" => Ok(FileType::GeoParquet),
         _ => Err(Error::UnsupportedFileExtension(
             "❌ Unsupported file type".into(),
         )),
@@ -83,11 +497,148 @@ pub fn determine_file_type(input_file: &str) -> Result<FileType> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_row_select() {
+        let mut row = Row::new();
+        row.add(AcceptedTypes::Int(Some(1)));
+        row.add(AcceptedTypes::Text(Some("a".to_string())));
+        row.add(AcceptedTypes::Bool(Some(true)));
+        let row = row.select(&[2, 0]);
+        assert_eq!(row.columns.len(), 2);
+        assert!(matches!(row.columns[0], AcceptedTypes::Bool(Some(true))));
+        assert!(matches!(row.columns[1], AcceptedTypes::Int(Some(1))));
+    }
+
+    #[test]
+    fn test_stream_rows_panic() {
+        let rows = stream_rows(|sender| {
+            sender.send(Ok(Row::new())).unwrap();
+            panic!("corrupt feature");
+        });
+        let rows: Vec<Result<Row>> = rows.iter().collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].is_ok());
+        assert!(
+            matches!(&rows[1], Err(Error::ReaderPanicked(message)) if message.contains("corrupt feature"))
+        );
+    }
+
+    #[test]
+    fn test_convert_in_parallel_init_error() {
+        let (sender, receiver) = sync_channel(ROW_BUFFER_SIZE);
+        let items = (0..3).map(Ok);
+        convert_in_parallel(
+            items,
+            Some(2),
+            &sender,
+            || Err::<(), _>(Error::FailedValidation("no transformer".into())),
+            |_, _: i32| Ok(Row::new()),
+        )
+        .unwrap();
+        drop(sender);
+        let rows: Vec<Result<Row>> = receiver.iter().collect();
+        assert_eq!(rows.len(), 3);
+        assert!(rows.iter().all(|row| row.is_err()));
+    }
+
+    #[test]
+    fn test_null() {
+        assert!(matches!(
+            AcceptedTypes::null(&Type::INT8),
+            AcceptedTypes::BigInt(None)
+        ));
+        assert!(matches!(
+            AcceptedTypes::null(&Type::TEXT_ARRAY),
+            AcceptedTypes::Array(None)
+        ));
+        assert!(matches!(
+            AcceptedTypes::null(&Type::TEXT),
+            AcceptedTypes::Text(None)
+        ));
+    }
+
+    #[test]
+    fn test_schema_inference() {
+        let mut schema = SchemaInference::new(OnMixedTypes::Text);
+        schema.add("a", None).unwrap();
+        schema.add("b", Some(Type::INT8)).unwrap();
+        schema.add("a", Some(Type::BOOL)).unwrap();
+        schema.add("b", Some(Type::FLOAT8)).unwrap();
+        schema.add("c", Some(Type::INT8)).unwrap();
+        schema.add("c", Some(Type::BOOL)).unwrap();
+        schema.add("d", None).unwrap();
+        let types: Vec<Type> = schema
+            .finish()
+            .into_iter()
+            .map(|column| column.data_type)
+            .collect();
+        assert_eq!(
+            types,
+            vec![Type::BOOL, Type::FLOAT8, Type::TEXT, Type::TEXT]
+        );
+
+        let mut schema = SchemaInference::new(OnMixedTypes::Error);
+        schema.add("a", Some(Type::INT8)).unwrap();
+        assert!(schema.add("a", Some(Type::TEXT)).is_err());
+    }
+
+    #[test]
+    fn test_coerce() {
+        let value = AcceptedTypes::Int(Some(7)).coerce(&Type::INT8);
+        assert!(matches!(value, AcceptedTypes::BigInt(Some(7))));
+        let value = AcceptedTypes::Double(Some(1.5)).coerce(&Type::FLOAT8);
+        assert!(matches!(value, AcceptedTypes::Float(Some(v)) if v == 1.5));
+        let value = AcceptedTypes::Bool(None).coerce(&Type::TEXT);
+        assert!(matches!(value, AcceptedTypes::Text(None)));
+        assert!(can_coerce(&Type::INT4, &Type::FLOAT8));
+        assert!(!can_coerce(&Type::TEXT, &Type::INT4));
+    }
+
+    #[test]
+    fn test_sanitize_column_names() {
+        let config = [
+            "Name",
+            "name",
+            "Año 2020",
+            "order",
+            "1st",
+            "geom",
+            "%",
+            "User",
+        ]
+        .iter()
+        .map(|column_name| NewTableTypes {
+            column_name: column_name.to_string(),
+            data_type: Type::TEXT,
+        })
+        .collect();
+        let names: Vec<String> = sanitize_column_names(config)
+            .into_iter()
+            .map(|column| column.column_name)
+            .collect();
+        assert_eq!(
+            names,
+            vec!["name", "name_1", "ano_2020", "order_", "_1st", "geom_1", "column_", "user_"]
+        );
+    }
+
     #[test]
     fn test_determine_file_type() {
         let shapefile = "examples/shapefile/andalucia.shp";
         let geojson = "examples/geojson/spain.geojson";
+        let geoparquet = "examples/geoparquet/spain.parquet";
         assert_eq!(determine_file_type(shapefile).unwrap(), FileType::Shapefile);
         assert_eq!(determine_file_type(geojson).unwrap(), FileType::GeoJson);
+        assert_eq!(
+            determine_file_type(geoparquet).unwrap(),
+            FileType::GeoParquet
+        );
+        for osmchange in [
+            "examples/osmchange/monaco.osc",
+            "examples/osmchange/monaco.osc.gz",
+        ] {
+            assert_eq!(determine_file_type(osmchange).unwrap(), FileType::OsmChange);
+        }
+        assert!(determine_file_type("examples/spain.csv.gz").is_err());
     }
 }
@@ -1,119 +1,551 @@
-use crate::{Result, Error};
-use geo::Coord;
-use shapefile::Shape;
+use crate::{Error, Result};
+use geo::{Area, Contains, Coord, LineString, MultiLineString, MultiPoint, MultiPolygon, Polygon};
+use proj::Proj;
+use shapefile::record::traits::HasXY;
+use shapefile::{Patch, PointM, PointZ, PolygonRing, Shape, NO_DATA};
+use std::cmp::Ordering;
 
-pub fn to_geo(shape: &Shape) -> Result<geo::Geometry<f64>> {
-    match shape {
-        Shape::Point(p) => Ok(geo::Point::new(p.x, p.y).into()),
-        Shape::Polyline(p) => {
-            let mut coords: Vec<Coord> = Vec::new();
-            for part in p.parts().iter() {
-                for point in part.iter() {
-                    coords.push(Coord::from((point.x, point.y)));
-                }
+// Coordinate dimensions of the shapes of a file, Z and M values are kept in the geometries
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Dims {
+    pub z: bool,
+    pub m: bool,
+}
+
+impl Dims {
+    // Measures are optional, files without any leave their M range at 0 or no data
+    pub fn from_header(header: &shapefile::header::Header) -> Self {
+        let [min, max] = header.bbox.m_range();
+        let measured = min > NO_DATA && !(min == 0.0 && max == 0.0);
+        Dims {
+            z: header.shape_type.has_z(),
+            m: header.shape_type.has_m() && measured,
+        }
+    }
+
+    // Type of the geometry column holding the shapes
+    pub fn geometry_type(self) -> &'static str {
+        match (self.z, self.m) {
+            (false, false) => "Geometry",
+            (true, false) => "GeometryZ",
+            (false, true) => "GeometryM",
+            (true, true) => "GeometryZM",
+        }
+    }
+}
+
+// Z and M of a shapefile point, 0 and no data for points without them
+trait Ordinates: HasXY + Copy {
+    fn zm(&self) -> (f64, f64) {
+        (0.0, NO_DATA)
+    }
+}
+
+impl Ordinates for shapefile::Point {}
+
+impl Ordinates for PointM {
+    fn zm(&self) -> (f64, f64) {
+        (0.0, self.m)
+    }
+}
+
+impl Ordinates for PointZ {
+    fn zm(&self) -> (f64, f64) {
+        (self.z, self.m)
+    }
+}
+
+fn to_coord<P: HasXY>(point: &P) -> Coord {
+    Coord::from((point.x(), point.y()))
+}
+
+fn to_line<P: HasXY>(points: &[P]) -> LineString {
+    points.iter().map(to_coord).collect()
+}
+
+// Rings of a shapefile polygon don't cross, so any vertex inside means the whole ring is
+fn contains_ring(outer: &Polygon, inner: &LineString) -> bool {
+    inner.points().any(|point| outer.contains(&point))
+}
+
+// Rings of each polygon of a shape, exterior first. Outer rings are clockwise, each one
+// starts a polygon, and holes belong to the smallest outer ring containing them
+fn polygon_parts<P: HasXY + Copy>(rings: &[PolygonRing<P>]) -> Vec<Vec<Vec<P>>> {
+    let mut polygons: Vec<Vec<Vec<P>>> = Vec::new();
+    let mut outers: Vec<Polygon> = Vec::new();
+    let mut inner_rings: Vec<&[P]> = Vec::new();
+    for ring in rings {
+        match ring {
+            PolygonRing::Outer(points) => {
+                polygons.push(vec![points.clone()]);
+                outers.push(Polygon::new(to_line(points), vec![]));
             }
-            Ok(geo::LineString::new(coords).into())
+            PolygonRing::Inner(points) => inner_rings.push(points),
         }
-        Shape::Polygon(p) => {
-            let mut outer_placeholder: Vec<(f64, f64)> = Vec::new();
-            let mut inner_rings: Vec<geo::LineString> = Vec::new();
+    }
 
-            for ring_type in p.rings() {
-                match ring_type {
-                    //Gather all outer rings
-                    shapefile::PolygonRing::Outer(out) => {
-                        out.iter().for_each(|p| outer_placeholder.push((p.x, p.y)))
-                    }
-                    //Gather all inner rings
-                    shapefile::PolygonRing::Inner(inn) => {
-                        let mut inner_ring: Vec<(f64, f64)> = Vec::new();
-                        inn.iter().for_each(|p| inner_ring.push((p.x, p.y)));
-                        let ls = geo::LineString::from(inner_ring);
-                        inner_rings.push(ls);
+    for inner_ring in inner_rings {
+        let line = to_line(inner_ring);
+        let parent = outers
+            .iter()
+            .enumerate()
+            .filter(|(_, outer)| contains_ring(outer, &line))
+            .min_by(|(_, a), (_, b)| {
+                a.unsigned_area()
+                    .partial_cmp(&b.unsigned_area())
+                    .unwrap_or(Ordering::Equal)
+            })
+            .map(|(index, _)| index);
+        match parent {
+            Some(index) => polygons[index].push(inner_ring.to_vec()),
+            // A hole outside every outer ring is wound the wrong way, keep it as an outer
+            None => polygons.push(vec![inner_ring.to_vec()]),
+        }
+    }
+    polygons
+}
+
+fn geo_multipoint<P: HasXY>(points: &[P]) -> geo::Geometry<f64> {
+    MultiPoint::new(points.iter().map(|point| to_coord(point).into()).collect()).into()
+}
+
+fn geo_lines<P: HasXY>(parts: &[Vec<P>], promote: bool) -> geo::Geometry<f64> {
+    let mut lines: Vec<LineString> = parts.iter().map(|part| to_line(part)).collect();
+    if lines.len() == 1 && !promote {
+        lines.remove(0).into()
+    } else {
+        MultiLineString::new(lines).into()
+    }
+}
+
+fn geo_polygons<P: HasXY + Copy>(rings: &[PolygonRing<P>], promote: bool) -> geo::Geometry<f64> {
+    let mut polygons: Vec<Polygon> = polygon_parts(rings)
+        .into_iter()
+        .map(|mut rings| {
+            let exterior = to_line(&rings.remove(0));
+            Polygon::new(exterior, rings.iter().map(|ring| to_line(ring)).collect())
+        })
+        .collect();
+    if polygons.len() == 1 && !promote {
+        polygons.remove(0).into()
+    } else {
+        MultiPolygon::new(polygons).into()
+    }
+}
+
+// Shape in two dimensions, Z and M values are dropped. Multipart shapes become multi
+// geometries, single part ones stay simple unless `promote` is set, so that every shape of
+// a file has the same geometry type. Null shapes have no geometry
+pub fn to_geo(shape: &Shape, promote: bool) -> Result<Option<geo::Geometry<f64>>> {
+    let geometry = match shape {
+        Shape::NullShape => return Ok(None),
+        Shape::Point(p) => geo::Point::from(to_coord(p)).into(),
+        Shape::PointM(p) => geo::Point::from(to_coord(p)).into(),
+        Shape::PointZ(p) => geo::Point::from(to_coord(p)).into(),
+        Shape::Multipoint(p) => geo_multipoint(p.points()),
+        Shape::MultipointM(p) => geo_multipoint(p.points()),
+        Shape::MultipointZ(p) => geo_multipoint(p.points()),
+        Shape::Polyline(p) => geo_lines(p.parts(), promote),
+        Shape::PolylineM(p) => geo_lines(p.parts(), promote),
+        Shape::PolylineZ(p) => geo_lines(p.parts(), promote),
+        Shape::Polygon(p) => geo_polygons(p.rings(), promote),
+        Shape::PolygonM(p) => geo_polygons(p.rings(), promote),
+        Shape::PolygonZ(p) => geo_polygons(p.rings(), promote),
+        Shape::Multipatch(_) => {
+            return Err(Error::UnsupportedShapeType(
+                "❌ Multipatch shapes are only read with their Z values".into(),
+            ))
+        }
+    };
+    Ok(Some(geometry))
+}
+
+// Faces of a multipatch: every triangle of strips and fans, and polygons made of rings,
+// where inner rings, and rings following a first ring, are holes of the ring before
+fn patch_faces(patches: &[Patch]) -> Vec<Vec<Vec<PointZ>>> {
+    let mut faces: Vec<Vec<Vec<PointZ>>> = Vec::new();
+    // Whether the last face is a polygon rings can be added to
+    let mut open = false;
+    for patch in patches {
+        match patch {
+            Patch::TriangleStrip(points) => {
+                for triangle in points.windows(3) {
+                    faces.push(vec![vec![
+                        triangle[0],
+                        triangle[1],
+                        triangle[2],
+                        triangle[0],
+                    ]]);
+                }
+                open = false;
+            }
+            Patch::TriangleFan(points) => {
+                if let Some((center, rest)) = points.split_first() {
+                    for edge in rest.windows(2) {
+                        faces.push(vec![vec![*center, edge[0], edge[1], *center]]);
                     }
                 }
+                open = false;
             }
-
-            let outer_ring = geo::LineString::from(outer_placeholder);
-            if inner_rings.is_empty() {
-                let poly = geo::Polygon::new(outer_ring, vec![]);
-                Ok(geo::Geometry::from(poly))
-            } else {
-                let poly = geo::Polygon::new(outer_ring, inner_rings);
-                Ok(geo::Geometry::from(poly))
+            Patch::OuterRing(points) | Patch::FirstRing(points) => {
+                faces.push(vec![points.clone()]);
+                open = true;
             }
+            Patch::InnerRing(points) | Patch::Ring(points) => match faces.last_mut() {
+                Some(face) if open => face.push(points.clone()),
+                _ => {
+                    faces.push(vec![points.clone()]);
+                    open = true;
+                }
+            },
         }
-        _ => Err(Error::UnsupportedShapeType("❌ Unsupported shape type".into())),
     }
+    faces
+}
+
+// Flags PostGIS sets on the geometry type of extended WKB
+const EWKB_Z: u32 = 0x8000_0000;
+const EWKB_M: u32 = 0x4000_0000;
+
+const WKB_POINT: u32 = 1;
+const WKB_LINESTRING: u32 = 2;
+const WKB_POLYGON: u32 = 3;
+const WKB_MULTIPOINT: u32 = 4;
+const WKB_MULTILINESTRING: u32 = 5;
+const WKB_MULTIPOLYGON: u32 = 6;
+const WKB_POLYHEDRALSURFACE: u32 = 15;
+
+// Little endian extended WKB with the dimensions of the file, reprojecting X and Y
+struct EwkbWriter<'a> {
+    dims: Dims,
+    proj: Option<&'a Proj>,
+    bytes: Vec<u8>,
+}
+
+impl EwkbWriter<'_> {
+    fn header(&mut self, geometry_type: u32) {
+        let mut geometry_type = geometry_type;
+        if self.dims.z {
+            geometry_type |= EWKB_Z;
+        }
+        if self.dims.m {
+            geometry_type |= EWKB_M;
+        }
+        self.bytes.push(1);
+        self.bytes.extend_from_slice(&geometry_type.to_le_bytes());
+    }
+
+    fn count(&mut self, count: usize) {
+        self.bytes.extend_from_slice(&(count as u32).to_le_bytes());
+    }
+
+    fn coord<P: Ordinates>(&mut self, point: &P) -> Result<()> {
+        let (x, y) = match self.proj {
+            Some(proj) => proj.convert((point.x(), point.y()))?,
+            None => (point.x(), point.y()),
+        };
+        let (z, m) = point.zm();
+        let mut values = vec![x, y];
+        if self.dims.z {
+            values.push(z);
+        }
+        if self.dims.m {
+            // Measures below the no data value aren't set
+            values.push(if m > NO_DATA { m } else { f64::NAN });
+        }
+        for value in values {
+            self.bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    fn coords<P: Ordinates>(&mut self, points: &[P]) -> Result<()> {
+        self.count(points.len());
+        for point in points {
+            self.coord(point)?;
+        }
+        Ok(())
+    }
+
+    fn point<P: Ordinates>(&mut self, point: &P) -> Result<()> {
+        self.header(WKB_POINT);
+        self.coord(point)
+    }
+
+    fn multipoint<P: Ordinates>(&mut self, points: &[P]) -> Result<()> {
+        self.header(WKB_MULTIPOINT);
+        self.count(points.len());
+        for point in points {
+            self.point(point)?;
+        }
+        Ok(())
+    }
+
+    fn line<P: Ordinates>(&mut self, points: &[P]) -> Result<()> {
+        self.header(WKB_LINESTRING);
+        self.coords(points)
+    }
+
+    fn lines<P: Ordinates>(&mut self, parts: &[Vec<P>], promote: bool) -> Result<()> {
+        if parts.len() == 1 && !promote {
+            return self.line(&parts[0]);
+        }
+        self.header(WKB_MULTILINESTRING);
+        self.count(parts.len());
+        for part in parts {
+            self.line(part)?;
+        }
+        Ok(())
+    }
+
+    fn polygon<P: Ordinates>(&mut self, rings: &[Vec<P>]) -> Result<()> {
+        self.header(WKB_POLYGON);
+        self.count(rings.len());
+        for ring in rings {
+            self.coords(ring)?;
+        }
+        Ok(())
+    }
+
+    fn polygons<P: Ordinates>(&mut self, rings: &[PolygonRing<P>], promote: bool) -> Result<()> {
+        let polygons = polygon_parts(rings);
+        if polygons.len() == 1 && !promote {
+            return self.polygon(&polygons[0]);
+        }
+        self.header(WKB_MULTIPOLYGON);
+        self.count(polygons.len());
+        for polygon in polygons.iter() {
+            self.polygon(polygon)?;
+        }
+        Ok(())
+    }
+
+    fn surface(&mut self, patches: &[Patch]) -> Result<()> {
+        let faces = patch_faces(patches);
+        self.header(WKB_POLYHEDRALSURFACE);
+        self.count(faces.len());
+        for face in faces.iter() {
+            self.polygon(face)?;
+        }
+        Ok(())
+    }
+}
+
+// Shape as extended WKB keeping its Z and M values, with the same geometry types as
+// `to_geo`. Multipatches become polyhedral surfaces. Null shapes have no geometry
+pub fn to_ewkb(
+    shape: &Shape,
+    dims: Dims,
+    promote: bool,
+    proj: Option<&Proj>,
+) -> Result<Option<Vec<u8>>> {
+    let mut writer = EwkbWriter {
+        dims,
+        proj,
+        bytes: Vec::new(),
+    };
+    match shape {
+        Shape::NullShape => return Ok(None),
+        Shape::Point(p) => writer.point(p)?,
+        Shape::PointM(p) => writer.point(p)?,
+        Shape::PointZ(p) => writer.point(p)?,
+        Shape::Multipoint(p) => writer.multipoint(p.points())?,
+        Shape::MultipointM(p) => writer.multipoint(p.points())?,
+        Shape::MultipointZ(p) => writer.multipoint(p.points())?,
+        Shape::Polyline(p) => writer.lines(p.parts(), promote)?,
+        Shape::PolylineM(p) => writer.lines(p.parts(), promote)?,
+        Shape::PolylineZ(p) => writer.lines(p.parts(), promote)?,
+        Shape::Polygon(p) => writer.polygons(p.rings(), promote)?,
+        Shape::PolygonM(p) => writer.polygons(p.rings(), promote)?,
+        Shape::PolygonZ(p) => writer.polygons(p.rings(), promote)?,
+        Shape::Multipatch(p) => writer.surface(p.patches())?,
+    }
+    Ok(Some(writer.bytes))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use shapefile::{Point, Polyline};
+    use shapefile::{Point, PolygonRing, Polyline};
 
     #[test]
     fn test_to_geo_point() {
         let shape = Shape::Point(shapefile::Point::new(1.0, 2.0));
-        let geo = to_geo(&shape).unwrap();
+        let geo = to_geo(&shape, false).unwrap().unwrap();
         assert_eq!(geo, geo::Geometry::Point(geo::Point::new(1.0, 2.0)));
     }
 
     #[test]
     fn test_to_geo_line() {
-        let first_part = vec![
-            Point::new(1.0, 1.0),
-            Point::new(2.0, 2.0),
-        ];
+        let first_part = vec![Point::new(1.0, 1.0), Point::new(2.0, 2.0)];
+        let shape = Shape::Polyline(Polyline::new(first_part.clone()));
+        let line = geo::LineString::from(vec![(1.0, 1.0), (2.0, 2.0)]);
+        assert_eq!(
+            to_geo(&shape, false).unwrap().unwrap(),
+            geo::Geometry::LineString(line.clone())
+        );
+        // Promoted, a single part is a multilinestring of one line
+        assert_eq!(
+            to_geo(&shape, true).unwrap().unwrap(),
+            geo::Geometry::MultiLineString(MultiLineString::new(vec![line]))
+        );
 
-        let second_part = vec![
-            Point::new(3.0, 1.0),
-            Point::new(5.0, 6.0),
+        let second_part = vec![Point::new(3.0, 1.0), Point::new(5.0, 6.0)];
+        let third_part = vec![
+            Point::new(17.0, 15.0),
+            Point::new(18.0, 19.0),
+            Point::new(20.0, 19.0),
         ];
-
-        let poly = Polyline::with_parts(vec![first_part, second_part]);
+        let poly = Polyline::with_parts(vec![first_part, second_part, third_part]);
         let shape = Shape::Polyline(poly);
-        let geo = to_geo(&shape).unwrap();
-        let expected = geo::Geometry::LineString(geo::LineString::from(vec![
-            Coord::from((1.0, 1.0)),
-            Coord::from((2.0, 2.0)),
-            Coord::from((3.0, 1.0)),
-            Coord::from((5.0, 6.0)),
+        let geo = to_geo(&shape, false).unwrap().unwrap();
+        // Parts are not joined together
+        let expected = geo::Geometry::MultiLineString(MultiLineString::new(vec![
+            geo::LineString::from(vec![(1.0, 1.0), (2.0, 2.0)]),
+            geo::LineString::from(vec![(3.0, 1.0), (5.0, 6.0)]),
+            geo::LineString::from(vec![(17.0, 15.0), (18.0, 19.0), (20.0, 19.0)]),
         ]));
         assert_eq!(geo, expected);
     }
 
+    fn square(x: f64, y: f64, size: f64) -> Vec<Point> {
+        vec![
+            Point::new(x, y),
+            Point::new(x, y + size),
+            Point::new(x + size, y + size),
+            Point::new(x + size, y),
+            Point::new(x, y),
+        ]
+    }
+
     #[test]
     fn test_to_geo_poly() {
+        let shape = Shape::Polygon(shapefile::Polygon::with_rings(vec![
+            PolygonRing::Outer(square(0.0, 0.0, 10.0)),
+            PolygonRing::Inner(square(2.0, 2.0, 2.0)),
+        ]));
+        let geo::Geometry::Polygon(polygon) = to_geo(&shape, false).unwrap().unwrap() else {
+            panic!("❌ Expected a polygon");
+        };
+        assert_eq!(polygon.interiors().len(), 1);
+        let geo::Geometry::MultiPolygon(polygons) = to_geo(&shape, true).unwrap().unwrap() else {
+            panic!("❌ Expected a multipolygon");
+        };
+        assert_eq!(polygons.0, vec![polygon]);
 
-        let first_part = vec![
+        // Holes go to the outer ring they are in, whatever the order of the rings
+        let shape = Shape::Polygon(shapefile::Polygon::with_rings(vec![
+            PolygonRing::Outer(square(0.0, 0.0, 10.0)),
+            PolygonRing::Outer(square(20.0, 0.0, 10.0)),
+            PolygonRing::Inner(square(22.0, 2.0, 2.0)),
+            PolygonRing::Inner(square(2.0, 2.0, 2.0)),
+            PolygonRing::Inner(square(6.0, 6.0, 2.0)),
+        ]));
+        let geo::Geometry::MultiPolygon(polygons) = to_geo(&shape, false).unwrap().unwrap() else {
+            panic!("❌ Expected a multipolygon");
+        };
+        assert_eq!(polygons.0.len(), 2);
+        assert_eq!(polygons.0[0].interiors().len(), 2);
+        assert_eq!(polygons.0[1].interiors().len(), 1);
+        assert_eq!(polygons.unsigned_area(), 188.0);
+    }
+
+    #[test]
+    fn test_to_geo_multipoint_and_null() {
+        let shape = Shape::Multipoint(shapefile::Multipoint::new(vec![
             Point::new(1.0, 1.0),
             Point::new(2.0, 2.0),
-        ];
+        ]));
+        let expected = geo::Geometry::MultiPoint(MultiPoint::from(vec![(1.0, 1.0), (2.0, 2.0)]));
+        assert_eq!(to_geo(&shape, false).unwrap(), Some(expected));
+        assert_eq!(to_geo(&Shape::NullShape, false).unwrap(), None);
+        assert_eq!(
+            to_ewkb(&Shape::NullShape, Dims::default(), false, None).unwrap(),
+            None
+        );
+    }
 
-        let second_part = vec![
-            Point::new(3.0, 1.0),
-            Point::new(5.0, 6.0),
-        ];
+    fn read_f64(bytes: &[u8], offset: usize) -> f64 {
+        f64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
 
-        let third_part = vec![
-            Point::new(17.0, 15.0),
-            Point::new(18.0, 19.0),
-            Point::new(20.0, 19.0),
+    #[test]
+    fn test_to_ewkb() {
+        let xyz = Dims { z: true, m: false };
+        let shape = Shape::PointZ(PointZ::new(1.0, 2.0, 3.0, NO_DATA));
+        let bytes = to_ewkb(&shape, xyz, false, None).unwrap().unwrap();
+        assert_eq!(bytes.len(), 1 + 4 + 3 * 8);
+        assert_eq!(bytes[1..5], (WKB_POINT | EWKB_Z).to_le_bytes());
+        assert_eq!(read_f64(&bytes, 21), 3.0);
+
+        // Measures that aren't set are NaN
+        let xyzm = Dims { z: true, m: true };
+        let bytes = to_ewkb(&shape, xyzm, false, None).unwrap().unwrap();
+        assert_eq!(bytes[1..5], (WKB_POINT | EWKB_Z | EWKB_M).to_le_bytes());
+        assert!(read_f64(&bytes, 29).is_nan());
+
+        let xym = Dims { z: false, m: true };
+        let line = shapefile::PolylineM::new(vec![
+            PointM::new(0.0, 0.0, 0.0),
+            PointM::new(1.0, 0.0, 10.0),
+        ]);
+        let bytes = to_ewkb(&Shape::PolylineM(line.clone()), xym, false, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(bytes[1..5], (WKB_LINESTRING | EWKB_M).to_le_bytes());
+        assert_eq!(bytes.len(), 1 + 4 + 4 + 2 * 3 * 8);
+        assert_eq!(read_f64(&bytes, 9 + 5 * 8), 10.0);
+        let bytes = to_ewkb(&Shape::PolylineM(line), xym, true, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(bytes[1..5], (WKB_MULTILINESTRING | EWKB_M).to_le_bytes());
+    }
+
+    #[test]
+    fn test_patch_faces() {
+        let point = |x: f64, y: f64| PointZ::new(x, y, 1.0, NO_DATA);
+        let patches = vec![
+            // Two triangles
+            Patch::TriangleStrip(vec![
+                point(0.0, 0.0),
+                point(1.0, 0.0),
+                point(0.0, 1.0),
+                point(1.0, 1.0),
+            ]),
+            // Three triangles around the first point
+            Patch::TriangleFan(vec![
+                point(0.0, 0.0),
+                point(1.0, 0.0),
+                point(1.0, 1.0),
+                point(0.0, 1.0),
+                point(-1.0, 1.0),
+            ]),
+            // A polygon with a hole
+            Patch::OuterRing(
+                square(0.0, 0.0, 10.0)
+                    .iter()
+                    .map(|p| point(p.x, p.y))
+                    .collect(),
+            ),
+            Patch::InnerRing(
+                square(2.0, 2.0, 2.0)
+                    .iter()
+                    .map(|p| point(p.x, p.y))
+                    .collect(),
+            ),
         ];
-        let poly = Polyline::with_parts(vec![first_part, second_part, third_part]);
-        let shape = Shape::Polyline(poly);
-        let geo = to_geo(&shape).unwrap();
-        let expected = geo::Geometry::LineString(geo::LineString::from(vec![
-            Coord::from((1.0, 1.0)),
-            Coord::from((2.0, 2.0)),
-            Coord::from((3.0, 1.0)),
-            Coord::from((5.0, 6.0)),
-            Coord::from((17.0, 15.0)),
-            Coord::from((18.0, 19.0)),
-            Coord::from((20.0, 19.0)),
-        ]));
-        assert_eq!(geo, expected);
+        let faces = patch_faces(&patches);
+        assert_eq!(faces.len(), 6);
+        assert!(faces[..5]
+            .iter()
+            .all(|face| face.len() == 1 && face[0].len() == 4));
+        assert_eq!(faces[5].len(), 2);
 
+        let shape = Shape::Multipatch(shapefile::Multipatch::with_parts(patches));
+        let dims = Dims { z: true, m: false };
+        let bytes = to_ewkb(&shape, dims, false, None).unwrap().unwrap();
+        assert_eq!(bytes[1..5], (WKB_POLYHEDRALSURFACE | EWKB_Z).to_le_bytes());
+        assert_eq!(bytes[5..9], 6u32.to_le_bytes());
+        assert!(to_geo(&shape, false).is_err());
     }
 }
@@ -1,168 +1,197 @@
 use crate::utils::cli::Cli;
+use crate::utils::crs::transformer;
 use crate::{Error, Result};
 
-use geojson::GeoJson;
+use geojson::{Feature, FeatureReader, FeatureWriter, JsonObject};
 use postgres::types::Type;
-use proj::{Proj, Transform};
+use proj::Transform;
 use serde_json;
-use std::collections::HashMap;
-use wkb::geom_to_wkb;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use wkb::{geom_to_wkb, wkb_to_geom};
 
-use crate::format::common::{AcceptedTypes, Row, Rows};
+use crate::format::common::{
+    convert_in_parallel, report_extra_columns, stream_rows, AcceptedTypes, OnMixedTypes, Row,
+    RowStream, SchemaInference,
+};
 use crate::pg::binary_copy::Wkb;
 
 use super::common::NewTableTypes;
 
-pub fn determine_data_types(file_path: &str) -> Result<Vec<NewTableTypes>> {
-    let mut table_config: HashMap<String, Type> = HashMap::new();
-    let geojson_str = std::fs::read_to_string(file_path)?;
-    let geojson = geojson_str.parse::<GeoJson>().unwrap();
-
-    match geojson {
-        GeoJson::FeatureCollection(fc) => {
-            let features = fc.features;
-            // Id not used
-            // table_config.push(NewTableTypes {
-            //     column_name: "id".to_string(),
-            //     data_type: Type::INT8,
-            // });
-            if !features.is_empty() {
-                let properties = features.first();
-                if properties.is_some() {
-                    for (key, value) in properties.unwrap().properties.clone().unwrap().into_iter()
-                    {
-                        if key == "geom" || key == "geometry" {
-                            continue;
-                        }
-                        match value {
-                            serde_json::Value::Number(_) => {
-                                if table_config.contains_key(&key)
-                                    && table_config[&key] == Type::FLOAT8
-                                {
-                                    continue;
-                                } else if table_config.contains_key(&key)
-                                    && table_config[&key] != Type::INT8
-                                {
-                                    return Err(Error::MixedDataTypes(
-                                        "❌ Column contains mixed data types".to_string(),
-                                    ));
-                                } else {
-                                    table_config.insert(key, Type::FLOAT8);
-                                }
-                            }
-                            serde_json::Value::String(_) => {
-                                if table_config.contains_key(&key)
-                                    && table_config[&key] == Type::TEXT
-                                {
-                                    continue;
-                                } else if table_config.contains_key(&key)
-                                    && table_config[&key] != Type::INT8
-                                {
-                                    return Err(Error::MixedDataTypes(
-                                        "❌ Column contains mixed data types".to_string(),
-                                    ));
-                                } else {
-                                    table_config.insert(key, Type::TEXT);
-                                }
-                            }
-                            serde_json::Value::Bool(_) => {
-                                if table_config.contains_key(&key)
-                                    && table_config[&key] == Type::BOOL
-                                {
-                                    continue;
-                                } else if table_config.contains_key(&key)
-                                    && table_config[&key] != Type::INT8
-                                {
-                                    return Err(Error::MixedDataTypes(
-                                        "❌ Column contains mixed data types".to_string(),
-                                    ));
-                                } else {
-                                    table_config.insert(key, Type::BOOL);
-                                }
-                            }
-                            // If null
-                            serde_json::Value::Null => continue,
-                            _ => println!("❌ Type currently not supported"),
-                        }
+// Infer column types from the first `sample` features, or all of them
+pub fn determine_data_types(
+    file_path: &str,
+    sample: Option<usize>,
+    on_mixed_types: OnMixedTypes,
+) -> Result<Vec<NewTableTypes>> {
+    let mut schema = SchemaInference::new(on_mixed_types);
+    let file = BufReader::new(File::open(file_path)?);
+    let mut unsupported: HashSet<String> = HashSet::new();
+
+    for feature in FeatureReader::from_reader(file)
+        .features()
+        .take(sample.unwrap_or(usize::MAX))
+    {
+        for (key, value) in feature?.properties.unwrap_or_default().into_iter() {
+            if key == "geom" || key == "geometry" {
+                continue;
+            }
+            let data_type = match value {
+                serde_json::Value::Number(num) if num.is_f64() => Some(Type::FLOAT8),
+                serde_json::Value::Number(_) => Some(Type::INT8),
+                serde_json::Value::String(_) => Some(Type::TEXT),
+                serde_json::Value::Bool(_) => Some(Type::BOOL),
+                serde_json::Value::Null => None,
+                _ => {
+                    if unsupported.insert(key.clone()) {
+                        println!("❌ Type currently not supported: {}", key);
                     }
+                    continue;
                 }
-            }
+            };
+            schema.add(&key, data_type)?;
         }
-        _ => println!("❌ Not a feature collection"),
-    }
-
-    let mut data_types: Vec<NewTableTypes> = Vec::new();
-    for (column_name, data_type) in table_config {
-        data_types.push(NewTableTypes {
-            column_name,
-            data_type,
-        });
     }
 
-    Ok(data_types)
+    Ok(schema.finish())
 }
 
-pub fn read_geojson(args: &Cli) -> Result<Rows> {
-    let mut rows = Rows::new();
-    let geojson_str = std::fs::read_to_string(&args.input)?;
-    let geojson = geojson_str.parse::<GeoJson>().unwrap();
-
-    match geojson {
-        GeoJson::FeatureCollection(fc) => {
-            let features = fc.features;
-            for feature in features {
-                let mut row = Row::new();
-                // Id not used
-                // let id = feature.id.unwrap();
-                // match id {
-                //     geojson::feature::Id::Number(id) => {
-                //         let as_i64 = id.as_i64().unwrap();
-                //         row.add(AcceptedTypes::Int(Some(as_i64 as i32)));
-                //     }
-                //     _ => (),
-                // }
-                for (_, value) in feature.properties.unwrap().into_iter() {
-                    match value {
-                        serde_json::Value::Number(num) => {
-                            row.add(AcceptedTypes::Float(num.as_f64()));
-                        }
-                        serde_json::Value::String(string) => {
-                            row.add(AcceptedTypes::Text(Some(string)));
-                        }
-                        serde_json::Value::Bool(boolean) => {
-                            row.add(AcceptedTypes::Bool(Some(boolean)));
-                        }
-                        serde_json::Value::Null => {
-                            row.add(AcceptedTypes::Text(None));
-                        }
-                        _ => println!("❌ Type currently not supported"),
-                    }
+// Numbers that don't fit an integer column, too large or not whole, fail the import
+fn to_value(value: serde_json::Value, column: &NewTableTypes) -> Result<AcceptedTypes> {
+    let data_type = &column.data_type;
+    let value = match value {
+        serde_json::Value::Number(num) => match *data_type {
+            Type::INT4 | Type::INT8 => match num.as_i64() {
+                Some(value) => AcceptedTypes::BigInt(Some(value)),
+                None => {
+                    return Err(Error::FailedValidation(format!(
+                        "❌ Value {} of column '{}' doesn't fit in {}",
+                        num,
+                        column.column_name,
+                        data_type.name()
+                    )))
                 }
-                let gj_geom = feature.geometry.unwrap();
-                let mut geom: geo::Geometry<f64> = gj_geom
-                    .value
-                    .try_into()
-                    .expect("❌ Failed to convert geojson::Geometry to geo::Geometry");
-                // Reproject
-                geom = if args.reproject.is_some() {
-                    let from = format!("EPSG:{}", args.srid.unwrap());
-                    let to = format!("EPSG:{}", args.reproject.unwrap());
-                    let proj = Proj::new_known_crs(&from, &to, None)?;
-                    geom.transform(&proj)?;
-                    geom
-                } else {
-                    geom
+            },
+            _ => AcceptedTypes::Float(num.as_f64()),
+        },
+        serde_json::Value::String(string) => AcceptedTypes::Text(Some(string)),
+        serde_json::Value::Bool(boolean) => AcceptedTypes::Bool(Some(boolean)),
+        _ => AcceptedTypes::null(data_type),
+    };
+    Ok(value.coerce(data_type))
+}
+
+// Rows hold one value per column of config, in config order, with the geometry last
+pub fn read_geojson(args: &Cli, config: &[NewTableTypes]) -> Result<RowStream> {
+    let args = args.clone();
+    let config = config.to_vec();
+    Ok(stream_rows(move |sender| {
+        let file = BufReader::new(File::open(&args.input)?);
+        // Definitions PROJ can't read fail here, before any thread needs them
+        transformer(&args)?;
+        let mut reported: HashSet<String> = HashSet::new();
+        // Properties are read in order, geometries converted and reprojected in parallel
+        let features = FeatureReader::from_reader(file).features().map(|feature| {
+            let feature = feature?;
+            let mut row = Row::new();
+            let mut properties = feature.properties.unwrap_or_default();
+            for column in config.iter() {
+                let value = match properties.remove(&column.column_name) {
+                    Some(value) => to_value(value, column)?,
+                    None => AcceptedTypes::null(&column.data_type),
+                };
+                row.add(value);
+            }
+            let extra = properties
+                .keys()
+                .filter(|key| *key != "geom" && *key != "geometry");
+            report_extra_columns(extra, &mut reported);
+            Ok((row, feature.geometry))
+        });
+        convert_in_parallel(
+            features,
+            args.threads,
+            sender,
+            || transformer(&args),
+            |proj, (mut row, gj_geom)| {
+                // Features without a geometry have a null geometry
+                let Some(gj_geom) = gj_geom else {
+                    row.add(AcceptedTypes::Geometry(None));
+                    return Ok(row);
                 };
-                let wkb = geom_to_wkb(&geom).expect("❌ Could not convert geometry to WKB");
-                // Check length of row
+                let mut geom: geo::Geometry<f64> = gj_geom.value.try_into()?;
+                // Reproject
+                if let Some(proj) = proj {
+                    geom.transform(proj)?;
+                }
+                let wkb = geom_to_wkb(&geom).map_err(|_| {
+                    Error::FailedValidation("❌ Could not convert geometry to WKB".into())
+                })?;
                 row.add(AcceptedTypes::Geometry(Some(Wkb { geometry: wkb })));
-                rows.add(row);
+                Ok(row)
+            },
+        )
+    }))
+}
+
+fn to_json(value: AcceptedTypes) -> serde_json::Value {
+    match value {
+        AcceptedTypes::Int(value) => value.into(),
+        AcceptedTypes::BigInt(value) => value.into(),
+        AcceptedTypes::Float(value) => value.into(),
+        AcceptedTypes::Double(value) => value.into(),
+        AcceptedTypes::Text(value) => value.into(),
+        AcceptedTypes::Bool(value) => value.into(),
+        AcceptedTypes::Array(value) => value.into(),
+        AcceptedTypes::Json(value) => value.unwrap_or_default(),
+        AcceptedTypes::Hstore(value) => value.map_or(serde_json::Value::Null, |tags| {
+            tags.into_iter()
+                .map(|(key, value)| (key, value.into()))
+                .collect::<serde_json::Map<String, serde_json::Value>>()
+                .into()
+        }),
+        AcceptedTypes::Geometry(_) => serde_json::Value::Null,
+    }
+}
+
+// Write rows, with values in config order and the geometry last, as a FeatureCollection
+pub fn write_geojson<I>(file_path: &str, config: &[NewTableTypes], rows: I) -> Result<u64>
+where
+    I: IntoIterator<Item = Result<Row>>,
+{
+    let file = BufWriter::new(File::create(file_path)?);
+    let mut writer = FeatureWriter::from_writer(file);
+    let mut count = 0;
+    for row in rows {
+        let mut values = row?.columns;
+        let geometry = match values.pop() {
+            Some(AcceptedTypes::Geometry(Some(wkb))) => {
+                let geom = wkb_to_geom(&mut wkb.geometry.as_slice()).map_err(|_| {
+                    Error::FailedValidation("❌ Could not read WKB geometry".into())
+                })?;
+                Some(geojson::Geometry::new((&geom).into()))
             }
+            _ => None,
+        };
+
+        let mut properties = JsonObject::new();
+        for (column, value) in config.iter().zip(values) {
+            properties.insert(column.column_name.clone(), to_json(value));
         }
-        _ => println!("❌ Not a feature collection"),
+
+        writer.write_feature(&Feature {
+            bbox: None,
+            geometry,
+            id: None,
+            properties: Some(properties),
+            foreign_members: None,
+        })?;
+        count += 1;
     }
+    writer.finish()?;
 
-    Ok(rows)
+    Ok(count)
 }
 
 #[cfg(test)]
@@ -172,7 +201,7 @@ mod tests {
     #[test]
     fn test_determine_data_types() {
         let file_path = "examples/geojson/spain.geojson";
-        let data_types = determine_data_types(file_path).unwrap();
+        let data_types = determine_data_types(file_path, None, OnMixedTypes::Text).unwrap();
         assert_eq!(data_types.len(), 3);
         for data_type in data_types {
             match data_type.column_name.as_str() {
@@ -184,6 +213,65 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_determine_data_types_all_features() {
+        let file_path = std::env::temp_dir().join("popgis_test_inference.geojson");
+        let file_path = file_path.to_str().unwrap();
+        let features = [
+            r#"{"a": null, "b": 1, "c": 1}"#,
+            r#"{"a": true, "b": 1.5, "c": "x", "d": 2}"#,
+        ]
+        .iter()
+        .map(|properties| {
+            format!(
+                r#"{{"type": "Feature", "properties": {}, "geometry": {{"type": "Point", "coordinates": [0, 0]}}}}"#,
+                properties
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+        std::fs::write(
+            file_path,
+            format!(
+                r#"{{"type": "FeatureCollection", "features": [{}]}}"#,
+                features
+            ),
+        )
+        .unwrap();
+
+        let data_types = determine_data_types(file_path, None, OnMixedTypes::Text).unwrap();
+        let data_types: Vec<(&str, Type)> = data_types
+            .iter()
+            .map(|column| (column.column_name.as_str(), column.data_type.clone()))
+            .collect();
+        assert_eq!(
+            data_types,
+            vec![
+                ("a", Type::BOOL),
+                ("b", Type::FLOAT8),
+                ("c", Type::TEXT),
+                ("d", Type::INT8)
+            ]
+        );
+        // Only the first feature is scanned
+        let data_types = determine_data_types(file_path, Some(1), OnMixedTypes::Error).unwrap();
+        assert_eq!(data_types.len(), 3);
+        assert!(determine_data_types(file_path, None, OnMixedTypes::Error).is_err());
+    }
+
+    #[test]
+    fn test_to_value_overflow() {
+        let column = NewTableTypes {
+            column_name: "population".to_string(),
+            data_type: Type::INT8,
+        };
+        let value = to_value(serde_json::json!(42), &column).unwrap();
+        assert!(matches!(value, AcceptedTypes::BigInt(Some(42))));
+        let result = to_value(serde_json::json!(u64::MAX), &column);
+        assert!(matches!(result, Err(Error::FailedValidation(message))
+            if message.contains("population") && message.contains(&u64::MAX.to_string())));
+    }
+
     #[test]
     fn test_read_geojson() {
         let file_path = "examples/geojson/spain.geojson";
@@ -195,8 +283,80 @@ mod tests {
             uri: "postgresql://localhost:5432/postgis".to_string(),
             schema: None,
             table: "spain".to_string(),
+            ..Default::default()
+        };
+        let config = determine_data_types(file_path, None, OnMixedTypes::Text).unwrap();
+        let rows = read_geojson(&args, &config).unwrap();
+        let rows = rows.iter().collect::<Result<Vec<Row>>>().unwrap();
+        assert_eq!(rows.len(), 19);
+    }
+
+    #[test]
+    fn test_read_geojson_by_name() {
+        let file_path = "examples/geojson/spain.geojson";
+        let args = Cli {
+            input: file_path.to_string(),
+            srid: Some(4326),
+            uri: "postgresql://localhost:5432/postgis".to_string(),
+            table: "spain".to_string(),
+            ..Default::default()
         };
-        let rows = read_geojson(&args).unwrap();
-        assert_eq!(rows.row.len(), 19);
+        // Values follow config order, whatever the order of the properties
+        let config = vec![
+            NewTableTypes {
+                column_name: "missing".to_string(),
+                data_type: Type::INT8,
+            },
+            NewTableTypes {
+                column_name: "name".to_string(),
+                data_type: Type::TEXT,
+            },
+        ];
+        let rows = read_geojson(&args, &config).unwrap();
+        let rows = rows.iter().collect::<Result<Vec<Row>>>().unwrap();
+        assert_eq!(rows.len(), 19);
+        for row in rows {
+            assert_eq!(row.columns.len(), 3);
+            assert!(matches!(row.columns[0], AcceptedTypes::BigInt(None)));
+            assert!(matches!(row.columns[1], AcceptedTypes::Text(Some(_))));
+        }
+    }
+
+    #[test]
+    fn test_write_geojson() {
+        let file_path = std::env::temp_dir().join("popgis_test_write.geojson");
+        let file_path = file_path.to_str().unwrap();
+        let config = vec![
+            NewTableTypes {
+                column_name: "name".to_string(),
+                data_type: Type::TEXT,
+            },
+            NewTableTypes {
+                column_name: "population".to_string(),
+                data_type: Type::INT8,
+            },
+        ];
+        let mut row = Row::new();
+        row.add(AcceptedTypes::Text(Some("Sevilla".to_string())));
+        row.add(AcceptedTypes::BigInt(Some(684234)));
+        let geom = geo::Geometry::Point(geo::Point::new(-5.98, 37.38));
+        row.add(AcceptedTypes::Geometry(Some(Wkb {
+            geometry: geom_to_wkb(&geom).unwrap(),
+        })));
+
+        let count = write_geojson(file_path, &config, vec![Ok(row)]).unwrap();
+        assert_eq!(count, 1);
+
+        let file = BufReader::new(File::open(file_path).unwrap());
+        let features = FeatureReader::from_reader(file)
+            .features()
+            .collect::<std::result::Result<Vec<Feature>, _>>()
+            .unwrap();
+        assert_eq!(features.len(), 1);
+        assert_eq!(features[0].property("name").unwrap(), "Sevilla");
+        assert_eq!(features[0].property("population").unwrap(), 684234);
+        let point: geo::Geometry<f64> = features[0].geometry.clone().unwrap().try_into().unwrap();
+        assert_eq!(point, geom);
+        std::fs::remove_file(file_path).unwrap();
     }
 }
@@ -0,0 +1,380 @@
+use crate::utils::cli::Cli;
+use crate::utils::crs::transformer;
+use crate::{Error, Result};
+
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Float64Type, Int32Type, Int64Type};
+use arrow_array::{Array, ArrayRef, RecordBatch};
+use arrow_cast::CastOptions;
+use arrow_schema::DataType;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use postgres::types::Type;
+use proj::{Proj, Transform};
+use std::fs::File;
+use wkb::{geom_to_wkb, wkb_to_geom};
+
+use crate::format::common::{stream_rows, AcceptedTypes, NewTableTypes, Row, RowStream};
+use crate::format::geo::Dims;
+use crate::pg::binary_copy::Wkb;
+
+// Geometry column as described by the file's `geo` metadata
+#[derive(Debug)]
+struct GeoColumn {
+    name: String,
+    encoding: String,
+    srid: Option<i32>,
+    dims: Dims,
+}
+
+// Dimensions of the geometries, from the types listed in the metadata. Types with Z values
+// have a " Z" suffix, and an empty list, meaning any type, is taken as 2D
+fn geometry_dims(column: &serde_json::Value) -> Result<Dims> {
+    let dims = |geometry_type: &str| match geometry_type.rsplit_once(' ') {
+        Some((_, "Z")) => Dims { z: true, m: false },
+        Some((_, "M")) => Dims { z: false, m: true },
+        Some((_, "ZM")) => Dims { z: true, m: true },
+        _ => Dims::default(),
+    };
+    let types: Vec<&str> = column["geometry_types"]
+        .as_array()
+        .map(|types| types.iter().filter_map(|t| t.as_str()).collect())
+        .unwrap_or_default();
+    let first = types.first().map(|t| dims(t)).unwrap_or_default();
+    if types.iter().any(|t| dims(t) != first) {
+        return Err(Error::FailedValidation(
+            "❌ GeoParquet geometry types mix 2D and 3D, they can't share a column".into(),
+        ));
+    }
+    Ok(first)
+}
+
+fn read_geo_metadata(file_path: &str) -> Result<GeoColumn> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(file_path)?)?;
+    let geo = builder
+        .metadata()
+        .file_metadata()
+        .key_value_metadata()
+        .and_then(|kv| kv.iter().find(|kv| kv.key == "geo"))
+        .and_then(|kv| kv.value.clone())
+        .ok_or(Error::FailedValidation(
+            "❌ No geo metadata found, not a GeoParquet file".into(),
+        ))?;
+    let geo: serde_json::Value = serde_json::from_str(&geo).map_err(|_| {
+        Error::FailedValidation("❌ Could not parse GeoParquet geo metadata".into())
+    })?;
+
+    let name = geo["primary_column"]
+        .as_str()
+        .ok_or(Error::FailedValidation(
+            "❌ GeoParquet metadata has no primary column".into(),
+        ))?
+        .to_string();
+    let column = &geo["columns"][&name];
+
+    // A missing crs means OGC:CRS84, which is 4326 with lon/lat axis order
+    let srid = match column.get("crs") {
+        None => Some(4326),
+        Some(crs) => match (crs["id"]["authority"].as_str(), crs["id"]["code"].as_i64()) {
+            (Some("EPSG"), Some(code)) => Some(code as i32),
+            (Some("OGC"), _) if crs["id"]["code"].as_str() == Some("CRS84") => Some(4326),
+            _ => None,
+        },
+    };
+
+    Ok(GeoColumn {
+        encoding: column["encoding"].as_str().unwrap_or("WKB").to_string(),
+        srid,
+        dims: geometry_dims(column)?,
+        name,
+    })
+}
+
+// Map an Arrow type to the Postgres type of the column it will be stored in
+fn arrow_to_pg(data_type: &DataType) -> Option<Type> {
+    match data_type {
+        DataType::Int8 | DataType::Int16 | DataType::Int32 | DataType::UInt8 | DataType::UInt16 => {
+            Some(Type::INT4)
+        }
+        DataType::Int64 | DataType::UInt32 | DataType::UInt64 => Some(Type::INT8),
+        DataType::Float16
+        | DataType::Float32
+        | DataType::Float64
+        | DataType::Decimal128(_, _)
+        | DataType::Decimal256(_, _) => Some(Type::FLOAT8),
+        DataType::Boolean => Some(Type::BOOL),
+        DataType::Utf8
+        | DataType::LargeUtf8
+        | DataType::Utf8View
+        | DataType::Date32
+        | DataType::Date64
+        | DataType::Timestamp(_, _)
+        | DataType::Time32(_)
+        | DataType::Time64(_) => Some(Type::TEXT),
+        _ => None,
+    }
+}
+
+// Arrow type each column is cast to before reading its values
+fn pg_to_arrow(data_type: &Type) -> DataType {
+    match *data_type {
+        Type::INT4 => DataType::Int32,
+        Type::INT8 => DataType::Int64,
+        Type::FLOAT8 => DataType::Float64,
+        Type::BOOL => DataType::Boolean,
+        _ => DataType::Utf8,
+    }
+}
+
+// Returns the SRID declared in the geo metadata, if it resolves to an EPSG code
+pub fn determine_srid(file_path: &str) -> Result<Option<i32>> {
+    let geo_column = read_geo_metadata(file_path)?;
+    Ok(geo_column.srid)
+}
+
+// Geometry column type, from the dimensions in the geo metadata
+pub fn geometry_type(file_path: &str) -> Result<&'static str> {
+    Ok(read_geo_metadata(file_path)?.dims.geometry_type())
+}
+
+pub fn determine_data_types(file_path: &str) -> Result<Vec<NewTableTypes>> {
+    let geo_column = read_geo_metadata(file_path)?;
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(file_path)?)?;
+
+    let mut data_types: Vec<NewTableTypes> = Vec::new();
+    for field in builder.schema().fields().iter() {
+        if field.name() == &geo_column.name {
+            continue;
+        }
+        match arrow_to_pg(field.data_type()) {
+            Some(data_type) => data_types.push(NewTableTypes {
+                column_name: field.name().clone(),
+                data_type,
+            }),
+            None => println!("❌ Type currently not supported: {}", field.name()),
+        }
+    }
+
+    Ok(data_types)
+}
+
+fn value_at(array: &ArrayRef, data_type: &Type, index: usize) -> AcceptedTypes {
+    let is_valid = array.is_valid(index);
+    match *data_type {
+        Type::INT4 => {
+            AcceptedTypes::Int(is_valid.then(|| array.as_primitive::<Int32Type>().value(index)))
+        }
+        Type::INT8 => {
+            AcceptedTypes::BigInt(is_valid.then(|| array.as_primitive::<Int64Type>().value(index)))
+        }
+        Type::FLOAT8 => {
+            AcceptedTypes::Float(is_valid.then(|| array.as_primitive::<Float64Type>().value(index)))
+        }
+        Type::BOOL => AcceptedTypes::Bool(is_valid.then(|| array.as_boolean().value(index))),
+        _ => {
+            AcceptedTypes::Text(is_valid.then(|| array.as_string::<i32>().value(index).to_string()))
+        }
+    }
+}
+
+fn batch_to_rows(
+    batch: &RecordBatch,
+    config: &[NewTableTypes],
+    geo_column: &GeoColumn,
+    proj: Option<&Proj>,
+) -> Result<Vec<Row>> {
+    // Cast every column once per batch so values can be read with a single type. Values
+    // that don't fit, e.g. UInt64 above the INT8 range, fail rather than turn into NULLs
+    let options = CastOptions {
+        safe: false,
+        ..Default::default()
+    };
+    let mut columns: Vec<ArrayRef> = Vec::new();
+    for column in config.iter() {
+        let array = batch.column_by_name(&column.column_name).ok_or_else(|| {
+            Error::FailedValidation(format!(
+                "❌ Column '{}' missing from a row group",
+                column.column_name
+            ))
+        })?;
+        let data_type = pg_to_arrow(&column.data_type);
+        let array = arrow_cast::cast_with_options(array, &data_type, &options).map_err(|err| {
+            Error::FailedValidation(format!(
+                "❌ Column '{}' doesn't fit in {}: {}",
+                column.column_name,
+                column.data_type.name(),
+                err
+            ))
+        })?;
+        columns.push(array);
+    }
+    let geometries = batch.column_by_name(&geo_column.name).ok_or_else(|| {
+        Error::FailedValidation(format!(
+            "❌ Geometry column '{}' missing from a row group",
+            geo_column.name
+        ))
+    })?;
+    let geometries = arrow_cast::cast(geometries, &DataType::Binary)?;
+    let geometries = geometries.as_binary::<i32>();
+
+    let mut rows = Vec::new();
+    for index in 0..batch.num_rows() {
+        let mut row = Row::new();
+        for (array, column) in columns.iter().zip(config.iter()) {
+            row.add(value_at(array, &column.data_type, index));
+        }
+
+        let wkb = if geometries.is_null(index) {
+            None
+        } else if let Some(proj) = proj {
+            // Reprojecting needs a decode, otherwise the WKB is copied as is
+            let mut geom = wkb_to_geom(&mut geometries.value(index))
+                .map_err(|_| Error::FailedValidation("❌ Could not read WKB geometry".into()))?;
+            geom.transform(proj)?;
+            Some(geom_to_wkb(&geom).map_err(|_| {
+                Error::FailedValidation("❌ Could not convert geometry to WKB".into())
+            })?)
+        } else {
+            Some(geometries.value(index).to_vec())
+        };
+        row.add(AcceptedTypes::Geometry(
+            wkb.map(|geometry| Wkb { geometry }),
+        ));
+        rows.push(row);
+    }
+
+    Ok(rows)
+}
+
+// Rows hold one value per column of config, in config order, with the geometry last
+pub fn read_geoparquet(args: &Cli, config: &[NewTableTypes]) -> Result<RowStream> {
+    let args = args.clone();
+    let config = config.to_vec();
+    Ok(stream_rows(move |sender| {
+        let geo_column = read_geo_metadata(&args.input)?;
+        if geo_column.encoding != "WKB" {
+            return Err(Error::FailedValidation(format!(
+                "❌ Unsupported GeoParquet encoding: {}",
+                geo_column.encoding
+            )));
+        }
+        // One transformer for the whole file
+        let proj = transformer(&args)?;
+        // Geometries are reprojected as 2D, which would drop their Z or M values
+        if proj.is_some() && geo_column.dims != Dims::default() {
+            return Err(Error::FailedValidation(
+                "❌ Reprojecting GeoParquet with Z or M values is not supported".into(),
+            ));
+        }
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&args.input)?)?.build()?;
+        for batch in reader {
+            for row in batch_to_rows(&batch?, &config, &geo_column, proj.as_ref())? {
+                // Writer has gone away, stop reading
+                if sender.send(Ok(row)).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_determine_srid() {
+        let file_path = "examples/geoparquet/spain.parquet";
+        assert_eq!(determine_srid(file_path).unwrap(), Some(4326));
+    }
+
+    #[test]
+    fn test_determine_data_types() {
+        let file_path = "examples/geoparquet/spain.parquet";
+        let data_types = determine_data_types(file_path).unwrap();
+        assert_eq!(data_types.len(), 4);
+        for data_type in data_types {
+            match data_type.column_name.as_str() {
+                "rank" => assert_eq!(data_type.data_type, Type::INT8),
+                _ => assert_eq!(data_type.data_type, Type::TEXT),
+            }
+        }
+    }
+
+    #[test]
+    fn test_geometry_dims() {
+        let column = serde_json::json!({"geometry_types": ["Polygon", "MultiPolygon"]});
+        assert_eq!(geometry_dims(&column).unwrap(), Dims::default());
+        let column = serde_json::json!({"geometry_types": ["Point Z"]});
+        assert_eq!(geometry_dims(&column).unwrap().geometry_type(), "GeometryZ");
+        let column = serde_json::json!({"geometry_types": []});
+        assert_eq!(geometry_dims(&column).unwrap(), Dims::default());
+        let column = serde_json::json!({"geometry_types": ["Point", "Point Z"]});
+        assert!(geometry_dims(&column).is_err());
+        assert_eq!(
+            geometry_type("examples/geoparquet/spain.parquet").unwrap(),
+            "Geometry"
+        );
+    }
+
+    #[test]
+    fn test_batch_to_rows_overflow() {
+        use arrow_array::{BinaryArray, UInt64Array};
+        use std::sync::Arc;
+
+        let point = geom_to_wkb(&geo::Geometry::Point(geo::Point::new(0.0, 0.0))).unwrap();
+        let geo_column = GeoColumn {
+            name: "geometry".to_string(),
+            encoding: "WKB".to_string(),
+            srid: Some(4326),
+            dims: Dims::default(),
+        };
+        let config = vec![NewTableTypes {
+            column_name: "count".to_string(),
+            data_type: Type::INT8,
+        }];
+        let batch = |count: u64| {
+            RecordBatch::try_from_iter([
+                (
+                    "count",
+                    Arc::new(UInt64Array::from(vec![count])) as ArrayRef,
+                ),
+                (
+                    "geometry",
+                    Arc::new(BinaryArray::from(vec![point.as_slice()])) as ArrayRef,
+                ),
+            ])
+            .unwrap()
+        };
+
+        let rows = batch_to_rows(&batch(42), &config, &geo_column, None).unwrap();
+        assert!(matches!(
+            rows[0].columns[0],
+            AcceptedTypes::BigInt(Some(42))
+        ));
+        let result = batch_to_rows(&batch(u64::MAX), &config, &geo_column, None);
+        assert!(
+            matches!(result, Err(Error::FailedValidation(message)) if message.contains("count"))
+        );
+    }
+
+    #[test]
+    fn test_read_geoparquet() {
+        let file_path = "examples/geoparquet/spain.parquet";
+        let args = Cli {
+            input: file_path.to_string(),
+            srid: Some(4326),
+            reproject: None,
+            mode: None,
+            uri: "postgresql://localhost:5432/postgis".to_string(),
+            schema: None,
+            table: "spain".to_string(),
+            ..Default::default()
+        };
+        let config = determine_data_types(file_path).unwrap();
+        let rows = read_geoparquet(&args, &config).unwrap();
+        let rows = rows.iter().collect::<Result<Vec<Row>>>().unwrap();
+        assert_eq!(rows.len(), 19);
+    }
+}
@@ -2,4 +2,5 @@ pub mod common;
 mod geo;
 pub mod shapefile;
 pub mod geojson;
+pub mod geoparquet;
 pub mod osmpbf;
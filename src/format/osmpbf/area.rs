@@ -0,0 +1,119 @@
+// Keys making a closed way an area
+pub const AREA_KEYS: [&str; 17] = [
+    "building",
+    "building:part",
+    "landuse",
+    "natural",
+    "amenity",
+    "leisure",
+    "shop",
+    "tourism",
+    "place",
+    "man_made",
+    "water",
+    "aeroway",
+    "military",
+    "historic",
+    "office",
+    "craft",
+    "healthcare",
+];
+
+// Keys making a closed way a line, e.g. a roundabout or a fence around a field
+pub const LINEAR_KEYS: [&str; 4] = ["highway", "barrier", "railway", "waterway"];
+
+// Values of area keys that describe lines
+const LINEAR_VALUES: [(&str, &str); 11] = [
+    ("natural", "coastline"),
+    ("natural", "cliff"),
+    ("natural", "ridge"),
+    ("natural", "arete"),
+    ("natural", "tree_row"),
+    ("man_made", "embankment"),
+    ("man_made", "pipeline"),
+    ("man_made", "cutline"),
+    ("man_made", "breakwater"),
+    ("aeroway", "runway"),
+    ("aeroway", "taxiway"),
+];
+
+// Decides whether a closed way is an area or a line
+#[derive(Debug, Clone)]
+pub struct AreaRules {
+    area_keys: Vec<String>,
+    linear_keys: Vec<String>,
+}
+
+impl Default for AreaRules {
+    fn default() -> Self {
+        AreaRules {
+            area_keys: AREA_KEYS.iter().map(|key| key.to_string()).collect(),
+            linear_keys: LINEAR_KEYS.iter().map(|key| key.to_string()).collect(),
+        }
+    }
+}
+
+impl AreaRules {
+    // Empty lists keep the defaults
+    pub fn new(area_keys: &[String], linear_keys: &[String]) -> Self {
+        let default = AreaRules::default();
+        AreaRules {
+            area_keys: if area_keys.is_empty() {
+                default.area_keys
+            } else {
+                area_keys.to_vec()
+            },
+            linear_keys: if linear_keys.is_empty() {
+                default.linear_keys
+            } else {
+                linear_keys.to_vec()
+            },
+        }
+    }
+
+    // `area=yes/no` wins, then linear keys, then area keys. Closed ways with none of them
+    // are lines
+    pub fn is_area<'a, I>(&self, tags: I) -> bool
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        let tags: Vec<(&str, &str)> = tags.into_iter().collect();
+        match tags.iter().find(|(key, _)| *key == "area") {
+            Some((_, "yes")) => return true,
+            Some((_, "no")) => return false,
+            _ => {}
+        }
+        if tags
+            .iter()
+            .any(|(key, _)| self.linear_keys.iter().any(|linear| linear == key))
+        {
+            return false;
+        }
+        tags.iter().any(|(key, value)| {
+            *value != "no"
+                && self.area_keys.iter().any(|area| area == key)
+                && !LINEAR_VALUES.contains(&(*key, *value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_area() {
+        let rules = AreaRules::default();
+        assert!(rules.is_area([("building", "yes")]));
+        assert!(rules.is_area([("highway", "pedestrian"), ("area", "yes")]));
+        assert!(!rules.is_area([("highway", "primary"), ("junction", "roundabout")]));
+        assert!(!rules.is_area([("building", "yes"), ("area", "no")]));
+        assert!(!rules.is_area([("natural", "coastline")]));
+        assert!(!rules.is_area([("building", "no")]));
+        assert!(!rules.is_area([]));
+
+        let rules = AreaRules::new(&["highway".to_string()], &["building".to_string()]);
+        assert!(!rules.is_area([("building", "yes")]));
+        assert!(rules.is_area([("highway", "primary")]));
+    }
+}
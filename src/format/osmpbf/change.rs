@@ -0,0 +1,615 @@
+use crate::format::common::Row;
+use crate::pg::osmchange::{
+    delete_features, delete_members, get_way_nodes, relations_table_name, relations_using_ways,
+    update_geometry, upsert_relation_ways, upsert_way_nodes, ways_table_name, ways_using_nodes,
+};
+use crate::utils::cli::Cli;
+use crate::utils::crs::transformer;
+use crate::{Error, Result};
+
+use flate2::read::GzDecoder;
+use geo::{Coord, Geometry, LineString, MultiPolygon, Point, Polygon};
+use postgres::GenericClient;
+use proj::{Proj, Transform};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use wkb::geom_to_wkb;
+
+use super::{
+    assemble_multipolygon, osmpbf_to_row, AreaRules, NodeStore, OsmFilter, OsmMetadata, OsmPbf,
+    OsmType, LAYERS,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    Create,
+    Modify,
+    Delete,
+}
+
+// An element as found in an OsmChange file, along with what happened to it
+#[derive(Debug)]
+struct Change {
+    action: Action,
+    osm_type: OsmType,
+    id: i64,
+    tags: Vec<(String, String)>,
+    metadata: OsmMetadata,
+    // Nodes only, in decimicro degrees
+    location: Option<(i32, i32)>,
+    // Ways only
+    nodes: Vec<i64>,
+    // Relations only, members that are ways
+    ways: Vec<i64>,
+}
+
+fn attribute(element: &BytesStart, name: &[u8]) -> Result<Option<String>> {
+    for attribute in element.attributes() {
+        let attribute = attribute.map_err(quick_xml::Error::from)?;
+        if attribute.key.as_ref() == name {
+            return Ok(Some(attribute.unescape_value()?.into_owned()));
+        }
+    }
+    Ok(None)
+}
+
+fn parse_attribute<T: std::str::FromStr>(element: &BytesStart, name: &[u8]) -> Result<Option<T>> {
+    match attribute(element, name)? {
+        Some(value) => value.parse().map(Some).map_err(|_| {
+            Error::FailedValidation(format!(
+                "❌ Invalid {} '{}' in OsmChange file",
+                String::from_utf8_lossy(name),
+                value
+            ))
+        }),
+        None => Ok(None),
+    }
+}
+
+// Degrees to the decimicro degrees node locations are stored in
+fn parse_coordinate(element: &BytesStart, name: &[u8]) -> Result<Option<i32>> {
+    Ok(parse_attribute::<f64>(element, name)?.map(|degrees| (degrees * 1e7).round() as i32))
+}
+
+fn start_change(element: &BytesStart, action: Action, osm_type: OsmType) -> Result<Change> {
+    let id = parse_attribute(element, b"id")?.ok_or(Error::FailedValidation(
+        "❌ Element without id in OsmChange file".into(),
+    ))?;
+    let location = match (
+        parse_coordinate(element, b"lon")?,
+        parse_coordinate(element, b"lat")?,
+    ) {
+        (Some(lon), Some(lat)) => Some((lon, lat)),
+        _ => None,
+    };
+    Ok(Change {
+        action,
+        osm_type,
+        id,
+        tags: Vec::new(),
+        metadata: OsmMetadata {
+            version: parse_attribute(element, b"version")?,
+            timestamp: attribute(element, b"timestamp")?,
+            changeset: parse_attribute(element, b"changeset")?,
+            uid: parse_attribute(element, b"uid")?,
+            user: attribute(element, b"user")?,
+        },
+        location,
+        nodes: Vec::new(),
+        ways: Vec::new(),
+    })
+}
+
+fn parse_osmchange<R: BufRead>(input: R) -> Result<Vec<Change>> {
+    let mut reader = Reader::from_reader(input);
+    let mut buffer = Vec::new();
+    let mut changes: Vec<Change> = Vec::new();
+    let mut action = None;
+    let mut current: Option<Change> = None;
+
+    loop {
+        let (element, closed) = match reader.read_event_into(&mut buffer)? {
+            Event::Start(element) => (element, false),
+            Event::Empty(element) => (element, true),
+            Event::End(element) => {
+                match element.name().as_ref() {
+                    b"create" | b"modify" | b"delete" => action = None,
+                    b"node" | b"way" | b"relation" => changes.extend(current.take()),
+                    _ => {}
+                }
+                buffer.clear();
+                continue;
+            }
+            Event::Eof => break,
+            _ => {
+                buffer.clear();
+                continue;
+            }
+        };
+
+        match element.name().as_ref() {
+            b"create" => action = Some(Action::Create),
+            b"modify" => action = Some(Action::Modify),
+            b"delete" => action = Some(Action::Delete),
+            name @ (b"node" | b"way" | b"relation") => {
+                let Some(action) = action else {
+                    return Err(Error::FailedValidation(
+                        "❌ Element outside of create, modify or delete in OsmChange file".into(),
+                    ));
+                };
+                let osm_type = match name {
+                    b"node" => OsmType::Node,
+                    b"way" => OsmType::Way,
+                    _ => OsmType::Relation,
+                };
+                let change = start_change(&element, action, osm_type)?;
+                if closed {
+                    changes.push(change);
+                } else {
+                    current = Some(change);
+                }
+            }
+            b"tag" => {
+                if let (Some(change), Some(key), Some(value)) = (
+                    current.as_mut(),
+                    attribute(&element, b"k")?,
+                    attribute(&element, b"v")?,
+                ) {
+                    change.tags.push((key, value));
+                }
+            }
+            b"nd" => {
+                if let (Some(change), Some(node_id)) =
+                    (current.as_mut(), parse_attribute(&element, b"ref")?)
+                {
+                    change.nodes.push(node_id);
+                }
+            }
+            b"member" => {
+                let is_way = attribute(&element, b"type")?.as_deref() == Some("way");
+                if let (Some(change), true, Some(way_id)) =
+                    (current.as_mut(), is_way, parse_attribute(&element, b"ref")?)
+                {
+                    change.ways.push(way_id);
+                }
+            }
+            _ => {}
+        }
+        buffer.clear();
+    }
+
+    Ok(changes)
+}
+
+// OsmChange files, as published by the OSM replication service, optionally gzipped
+fn read_osmchange(file_path: &str) -> Result<Vec<Change>> {
+    let file = File::open(file_path)?;
+    if file_path.ends_with(".gz") {
+        parse_osmchange(BufReader::new(GzDecoder::new(file)))
+    } else {
+        parse_osmchange(BufReader::new(file))
+    }
+}
+
+fn is_area_relation(tags: &[(String, String)]) -> bool {
+    tags.iter()
+        .any(|(key, value)| key == "type" && (value == "multipolygon" || value == "boundary"))
+}
+
+fn matches(filter: &OsmFilter, tags: &[(String, String)]) -> bool {
+    filter.matches(
+        tags.iter()
+            .map(|(key, value)| (key.as_str(), value.as_str())),
+    )
+}
+
+// Node locations changed by a file, None for nodes deleted. They are only written to the
+// node cache once the changes are committed, so a failure leaves it as it was
+pub struct NodeChanges(HashMap<i64, Option<(i32, i32)>>);
+
+impl NodeChanges {
+    fn get(&self, id: i64, store: &dyn NodeStore) -> Option<Coord<f64>> {
+        match self.0.get(&id) {
+            Some(Some((lon, lat))) => Some(Coord {
+                x: *lon as f64 * 1e-7,
+                y: *lat as f64 * 1e-7,
+            }),
+            Some(None) => None,
+            None => store.get(id),
+        }
+    }
+
+    pub fn apply(self, store: &mut dyn NodeStore) -> Result<()> {
+        for (id, location) in self.0 {
+            match location {
+                Some((lon, lat)) => store.insert(id, lon, lat)?,
+                None => store.remove(id),
+            }
+        }
+        store.finish()
+    }
+}
+
+// None when a node is missing from both the changes and the store
+fn way_coords(
+    nodes: &[i64],
+    node_changes: &NodeChanges,
+    store: &dyn NodeStore,
+) -> Option<Vec<Coord<f64>>> {
+    nodes
+        .iter()
+        .map(|node_id| node_changes.get(*node_id, store))
+        .collect()
+}
+
+fn is_polygon(nodes: &[i64], tags: &[(String, String)], area_rules: &AreaRules) -> bool {
+    nodes.len() >= 4
+        && nodes.first() == nodes.last()
+        && area_rules.is_area(
+            tags.iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        )
+}
+
+// Multipolygon of an area relation from the nodes of its ways as they are after the changes.
+// None when a way is missing or the rings don't close
+fn relation_geometry<C: GenericClient>(
+    client: &mut C,
+    ways_table: &str,
+    schema_name: &Option<String>,
+    way_ids: &[i64],
+    node_changes: &NodeChanges,
+    store: &dyn NodeStore,
+) -> Result<Option<MultiPolygon>> {
+    let member_ways = get_way_nodes(client, ways_table, schema_name, way_ids)?;
+    let ways: Option<Vec<Vec<Coord<f64>>>> = way_ids
+        .iter()
+        .map(|way_id| {
+            member_ways
+                .get(way_id)
+                .and_then(|nodes| way_coords(nodes, node_changes, store))
+        })
+        .collect();
+    Ok(ways.and_then(assemble_multipolygon))
+}
+
+// Node locations are in 4326, geometries are reprojected when the import was
+fn reproject(mut geometry: Geometry, proj: Option<&Proj>) -> Result<Geometry> {
+    if let Some(proj) = proj {
+        geometry.transform(proj)?;
+    }
+    Ok(geometry)
+}
+
+fn to_wkb(geometry: Geometry, proj: Option<&Proj>) -> Result<Vec<u8>> {
+    geom_to_wkb(&reproject(geometry, proj)?)
+        .map_err(|_| Error::FailedValidation("❌ Could not convert geometry to WKB".into()))
+}
+
+// Line and polygon of a way, for whichever layer holds it. Polygons are stored as
+// multipolygons. None when a node is missing or there are too few for a line
+fn way_geometries(
+    nodes: &[i64],
+    node_changes: &NodeChanges,
+    store: &dyn NodeStore,
+    proj: Option<&Proj>,
+) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let Some(coords) = way_coords(nodes, node_changes, store).filter(|c| c.len() >= 2) else {
+        return Ok(None);
+    };
+    let line = LineString::from(coords);
+    let polygon = MultiPolygon(vec![Polygon::new(line.clone(), vec![])]);
+    Ok(Some((
+        to_wkb(Geometry::LineString(line), proj)?,
+        to_wkb(Geometry::MultiPolygon(polygon), proj)?,
+    )))
+}
+
+// Apply the changes of an OsmChange file to the tables of an earlier import. Features of
+// every element changed are deleted, the rows returned are their new versions to insert.
+// Ways whose nodes moved, and area relations whose ways changed or moved, get their
+// geometry rebuilt in place
+pub fn apply_changes<C: GenericClient>(
+    client: &mut C,
+    args: &Cli,
+    store: &dyn NodeStore,
+) -> Result<(Vec<Row>, NodeChanges)> {
+    let filter = OsmFilter::parse(&args.osm_filter)?;
+    let area_rules = AreaRules::new(&args.area_keys, &args.linear_keys);
+    let ways_table = ways_table_name(&args.table);
+    let relations_table = relations_table_name(&args.table);
+    // Geometries are reprojected to, and written in, the SRID the import was made in
+    let srid = args.reproject.or(args.srid).unwrap();
+    let proj = transformer(args)?;
+    let layer_tables: Vec<String> = LAYERS
+        .iter()
+        .map(|(suffix, _)| format!("{}_{}", args.table, suffix))
+        .collect();
+
+    // Only the last version of an element counts when it changed more than once
+    let mut latest: HashMap<(&str, i64), Change> = HashMap::new();
+    let mut counts = [0; 3];
+    for change in read_osmchange(&args.input)? {
+        counts[change.osm_type as usize] += 1;
+        latest.insert((change.osm_type.code(), change.id), change);
+    }
+    println!(
+        "{} nodes, {} ways and {} relations changed",
+        counts[0], counts[1], counts[2]
+    );
+
+    // Node locations first, ways are built from them
+    let node_changes = NodeChanges(
+        latest
+            .values()
+            .filter(|change| change.osm_type == OsmType::Node)
+            .map(|change| match change.action {
+                Action::Delete => (change.id, None),
+                _ => (change.id, change.location),
+            })
+            .collect(),
+    );
+    let moved: Vec<i64> = node_changes.0.keys().copied().collect();
+
+    // Features of every element changed are replaced
+    let mut deleted = 0;
+    for osm_type in [OsmType::Node, OsmType::Way, OsmType::Relation] {
+        let ids: Vec<i64> = latest
+            .values()
+            .filter(|change| change.osm_type == osm_type)
+            .map(|change| change.id)
+            .collect();
+        if ids.is_empty() {
+            continue;
+        }
+        for table in layer_tables.iter() {
+            deleted += delete_features(client, table, &args.schema, osm_type.code(), &ids)?;
+        }
+    }
+    println!("✅ {} rows deleted", deleted);
+
+    // Nodes of the ways changed, kept for ways to be rebuilt later on
+    let deleted_ways: Vec<i64> = latest
+        .values()
+        .filter(|c| c.osm_type == OsmType::Way && c.action == Action::Delete)
+        .map(|change| change.id)
+        .collect();
+    delete_members(client, &ways_table, &args.schema, &deleted_ways)?;
+    let changed_ways: HashMap<i64, Vec<i64>> = latest
+        .values()
+        .filter(|c| c.osm_type == OsmType::Way && c.action != Action::Delete)
+        .map(|change| (change.id, change.nodes.clone()))
+        .collect();
+    upsert_way_nodes(client, &ways_table, &args.schema, &changed_ways)?;
+
+    // Ways of the area relations changed, kept for relations to be rebuilt later on
+    let (kept, dropped): (Vec<&Change>, Vec<&Change>) = latest
+        .values()
+        .filter(|c| c.osm_type == OsmType::Relation)
+        .partition(|c| {
+            c.action != Action::Delete && is_area_relation(&c.tags) && matches(&filter, &c.tags)
+        });
+    let dropped: Vec<i64> = dropped.iter().map(|change| change.id).collect();
+    delete_members(client, &relations_table, &args.schema, &dropped)?;
+    let changed_relations: HashMap<i64, Vec<i64>> = kept
+        .iter()
+        .map(|change| (change.id, change.ways.clone()))
+        .collect();
+    upsert_relation_ways(client, &relations_table, &args.schema, &changed_relations)?;
+    // Relations in the file are built along with the other elements
+    let mut rebuilt: HashSet<i64> = changed_relations.keys().copied().collect();
+    rebuilt.extend(dropped);
+
+    let mut items: Vec<OsmPbf> = Vec::new();
+    let mut changes: Vec<Change> = latest.into_values().collect();
+    // Relations are built once the ways they are made of are known
+    changes.sort_by_key(|change| change.osm_type as usize);
+    for change in changes {
+        if change.action == Action::Delete || !matches(&filter, &change.tags) {
+            continue;
+        }
+        let geometry = match change.osm_type {
+            OsmType::Node => match change.location {
+                Some((lon, lat)) if !change.tags.is_empty() => {
+                    Geometry::Point(Point::new(lon as f64 * 1e-7, lat as f64 * 1e-7))
+                }
+                _ => continue,
+            },
+            OsmType::Way => {
+                let polygon = is_polygon(&change.nodes, &change.tags, &area_rules);
+                let min_len = if polygon { 4 } else { 2 };
+                let Some(coords) = way_coords(&change.nodes, &node_changes, store)
+                    .filter(|coords| coords.len() >= min_len)
+                else {
+                    println!(
+                        "❌ Way {} has missing nodes or too few nodes, skipped",
+                        change.id
+                    );
+                    continue;
+                };
+                if polygon {
+                    Geometry::Polygon(Polygon::new(LineString::from(coords), vec![]))
+                } else {
+                    Geometry::LineString(LineString::from(coords))
+                }
+            }
+            OsmType::Relation => {
+                if !is_area_relation(&change.tags) {
+                    continue;
+                }
+                // Ways changed along with the relation are already in the ways table
+                match relation_geometry(
+                    client,
+                    &ways_table,
+                    &args.schema,
+                    &change.ways,
+                    &node_changes,
+                    store,
+                )? {
+                    Some(multipolygon) => Geometry::MultiPolygon(multipolygon),
+                    None => {
+                        println!(
+                            "❌ Multipolygon relation {} could not be assembled, skipped",
+                            change.id
+                        );
+                        continue;
+                    }
+                }
+            }
+        };
+        items.push(OsmPbf {
+            id: change.id,
+            osm_type: change.osm_type,
+            tags: change.tags,
+            metadata: Some(change.metadata),
+            geometry: reproject(geometry, proj.as_ref())?,
+        });
+    }
+
+    // Ways left as they were but made of nodes that moved
+    let mut updated = 0;
+    let mut touched: Vec<i64> = changed_ways.keys().copied().collect();
+    if !moved.is_empty() {
+        let ways = ways_using_nodes(client, &ways_table, &args.schema, &moved)?;
+        for (id, nodes) in ways.iter().filter(|(id, _)| !changed_ways.contains_key(id)) {
+            let Some((line, polygon)) = way_geometries(nodes, &node_changes, store, proj.as_ref())?
+            else {
+                println!("❌ Way {} has missing nodes or too few nodes, skipped", id);
+                continue;
+            };
+            let code = OsmType::Way.code();
+            updated += update_geometry(
+                client,
+                &layer_tables[1],
+                &args.schema,
+                code,
+                *id,
+                &line,
+                srid,
+            )?;
+            updated += update_geometry(
+                client,
+                &layer_tables[2],
+                &args.schema,
+                code,
+                *id,
+                &polygon,
+                srid,
+            )?;
+            touched.push(*id);
+        }
+    }
+
+    // Area relations left as they were but made of ways that changed or moved
+    if !touched.is_empty() {
+        let relations = relations_using_ways(client, &relations_table, &args.schema, &touched)?;
+        for (id, way_ids) in relations.iter().filter(|(id, _)| !rebuilt.contains(id)) {
+            let geometry = relation_geometry(
+                client,
+                &ways_table,
+                &args.schema,
+                way_ids,
+                &node_changes,
+                store,
+            )?;
+            let Some(multipolygon) = geometry else {
+                println!(
+                    "❌ Multipolygon relation {} could not be assembled, left as it was",
+                    id
+                );
+                continue;
+            };
+            let wkb = to_wkb(Geometry::MultiPolygon(multipolygon), proj.as_ref())?;
+            let code = OsmType::Relation.code();
+            updated += update_geometry(
+                client,
+                &layer_tables[2],
+                &args.schema,
+                code,
+                *id,
+                &wkb,
+                srid,
+            )?;
+        }
+    }
+    println!("✅ {} way and relation geometries updated", updated);
+
+    let rows = items
+        .into_iter()
+        .map(|item| osmpbf_to_row(item, args.tags, &args.columns, args.osm_metadata))
+        .collect();
+    Ok((rows, node_changes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::osmpbf::nodes::create_node_store;
+    use crate::format::osmpbf::NodeCache;
+    use wkb::wkb_to_geom;
+
+    #[test]
+    fn test_way_geometries_reprojected() {
+        let mut store = create_node_store(NodeCache::Sparse, None).unwrap();
+        store.insert(1, 0, 0).unwrap();
+        store.insert(2, 10_000_000, 0).unwrap();
+        store.finish().unwrap();
+        // Node 2 moves from 1° to 2° east in a change to an import made in 3857
+        let node_changes = NodeChanges(HashMap::from([(2, Some((20_000_000, 0)))]));
+        let args = Cli {
+            srid: Some(4326),
+            reproject: Some(3857),
+            ..Default::default()
+        };
+        let proj = transformer(&args).unwrap();
+        let (line, _) = way_geometries(&[1, 2], &node_changes, store.as_ref(), proj.as_ref())
+            .unwrap()
+            .unwrap();
+        let Geometry::LineString(line) = wkb_to_geom(&mut line.as_slice()).unwrap() else {
+            panic!("❌ Line not found");
+        };
+        assert!(line.0[0].x.abs() < 1e-6);
+        assert!((line.0[1].x - 222_638.98).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_is_polygon() {
+        let tags = vec![("building".to_string(), "yes".to_string())];
+        let area_rules = AreaRules::default();
+        assert!(is_polygon(&[1, 2, 3, 1], &tags, &area_rules));
+        assert!(!is_polygon(&[1, 2, 3], &tags, &area_rules));
+        assert!(!is_polygon(&[1, 2, 1], &tags, &area_rules));
+        assert!(!is_polygon(&[], &tags, &area_rules));
+    }
+
+    #[test]
+    fn test_read_osmchange() {
+        let changes = read_osmchange("examples/osmchange/monaco.osc").unwrap();
+        assert_eq!(changes.len(), 5);
+
+        let node = &changes[0];
+        assert_eq!(node.action, Action::Create);
+        assert_eq!(node.osm_type, OsmType::Node);
+        assert_eq!(node.location, Some((74_246_500, 437_384_300)));
+        assert_eq!(node.tags, vec![("amenity".into(), "cafe".into())]);
+        assert_eq!(node.metadata.version, Some(1));
+        assert_eq!(node.metadata.user.as_deref(), Some("popgis"));
+
+        let way = &changes[2];
+        assert_eq!(way.action, Action::Modify);
+        assert_eq!(way.nodes.len(), 3);
+
+        let relation = &changes[3];
+        assert_eq!(relation.osm_type, OsmType::Relation);
+        assert_eq!(relation.ways, vec![37794306, 37794307]);
+
+        assert_eq!(changes[4].action, Action::Delete);
+
+        // Gzipped files hold the same changes
+        let gzipped = read_osmchange("examples/osmchange/monaco.osc.gz").unwrap();
+        assert_eq!(gzipped.len(), changes.len());
+    }
+}
@@ -0,0 +1,117 @@
+use crate::{Error, Result};
+
+// A key, and the values it must have, any if none
+#[derive(Debug, Clone, PartialEq)]
+struct Condition {
+    key: String,
+    values: Option<Vec<String>>,
+}
+
+impl Condition {
+    fn matches(&self, tags: &[(&str, &str)]) -> bool {
+        tags.iter().any(|(key, value)| {
+            *key == self.key
+                && self
+                    .values
+                    .as_ref()
+                    .is_none_or(|values| values.iter().any(|v| v == value))
+        })
+    }
+}
+
+// Features kept from an OsmPbf file. A feature is kept when it matches any of the included
+// conditions, or there are none, and none of the excluded ones
+#[derive(Debug, Clone, Default)]
+pub struct OsmFilter {
+    include: Vec<Condition>,
+    exclude: Vec<Condition>,
+}
+
+impl OsmFilter {
+    // Expressions look like `highway=*`, `amenity=restaurant|cafe` or `!building`
+    pub fn parse(expressions: &[String]) -> Result<Self> {
+        let mut filter = OsmFilter::default();
+        for expression in expressions.iter() {
+            let (exclude, expression) = match expression.strip_prefix('!') {
+                Some(expression) => (true, expression),
+                None => (false, expression.as_str()),
+            };
+            let (key, values) = match expression.split_once('=') {
+                Some((key, "*")) => (key, None),
+                Some((key, values)) => (key, Some(values.split('|').map(String::from).collect())),
+                None => (expression, None),
+            };
+            if key.is_empty()
+                || values
+                    .as_ref()
+                    .is_some_and(|v: &Vec<String>| v.contains(&String::new()))
+            {
+                return Err(Error::FailedValidation(format!(
+                    "❌ Invalid OsmPbf filter: {}",
+                    expression
+                )));
+            }
+            let condition = Condition {
+                key: key.to_string(),
+                values,
+            };
+            if exclude {
+                filter.exclude.push(condition);
+            } else {
+                filter.include.push(condition);
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn matches<'a, I>(&self, tags: I) -> bool
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        if self.is_empty() {
+            return true;
+        }
+        let tags: Vec<(&str, &str)> = tags.into_iter().collect();
+        !self
+            .exclude
+            .iter()
+            .any(|condition| condition.matches(&tags))
+            && (self.include.is_empty()
+                || self
+                    .include
+                    .iter()
+                    .any(|condition| condition.matches(&tags)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(expressions: &[&str]) -> OsmFilter {
+        let expressions: Vec<String> = expressions.iter().map(|e| e.to_string()).collect();
+        OsmFilter::parse(&expressions).unwrap()
+    }
+
+    #[test]
+    fn test_osm_filter() {
+        let highways = filter(&["highway=*", "amenity=restaurant|cafe", "!building"]);
+        assert!(highways.matches([("highway", "primary")]));
+        assert!(highways.matches([("amenity", "cafe"), ("name", "Café de Paris")]));
+        assert!(!highways.matches([("amenity", "bank")]));
+        assert!(!highways.matches([("amenity", "cafe"), ("building", "yes")]));
+        assert!(!highways.matches([]));
+
+        let no_buildings = filter(&["!building"]);
+        assert!(no_buildings.matches([("natural", "water")]));
+        assert!(!no_buildings.matches([("building", "yes")]));
+
+        assert!(OsmFilter::default().matches([]));
+        assert!(OsmFilter::parse(&["=yes".to_string()]).is_err());
+        assert!(OsmFilter::parse(&["amenity=cafe|".to_string()]).is_err());
+    }
+}
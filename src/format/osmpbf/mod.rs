@@ -0,0 +1,902 @@
+use crate::format::common::{
+    convert_in_parallel, hstore_type, stream_rows, AcceptedTypes, NewTableTypes, Row, RowStream,
+};
+use crate::pg::binary_copy::Wkb;
+use crate::utils::cli::Cli;
+use crate::utils::crs::transformer;
+use crate::Result;
+use postgres::types::Type;
+use proj::Transform;
+use wkb::geom_to_wkb;
+
+use geo::{Coord, Geometry, LineString, Point, Polygon};
+use osmpbf::{Element, ElementReader, RelMemberType};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::Mutex;
+
+mod area;
+mod change;
+mod filter;
+mod multipolygon;
+mod nodes;
+pub mod routing;
+
+pub use area::AreaRules;
+pub use change::apply_changes;
+pub use filter::OsmFilter;
+use multipolygon::assemble_multipolygon;
+use nodes::{create_node_store, NodeStore};
+pub use nodes::{open_node_store, NodeCache};
+pub use routing::OsmProfile;
+
+// Tables an import is split into, suffix of their name and type of their geometry
+pub const LAYERS: [(&str, &str); 3] = [
+    ("point", "Point"),
+    ("line", "LineString"),
+    ("polygon", "MultiPolygon"),
+];
+
+// How tags are stored
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum TagStorage {
+    // TEXT[] of key=value
+    #[default]
+    Array,
+    Jsonb,
+    Hstore,
+}
+
+// Columns identifying the element each feature comes from
+pub const ID_COLUMNS: [&str; 2] = ["osm_id", "osm_type"];
+
+// Columns added with `--osm-metadata`
+pub const METADATA_COLUMNS: [&str; 5] = [
+    "osm_version",
+    "osm_timestamp",
+    "osm_changeset",
+    "osm_uid",
+    "osm_user",
+];
+
+// Kind of element a feature comes from, stored as N, W or R
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OsmType {
+    Node,
+    Way,
+    Relation,
+}
+
+impl OsmType {
+    fn code(self) -> &'static str {
+        match self {
+            OsmType::Node => "N",
+            OsmType::Way => "W",
+            OsmType::Relation => "R",
+        }
+    }
+}
+
+// Last edit of an element, any of it may be missing from a file
+#[derive(Debug, Default, Clone)]
+struct OsmMetadata {
+    version: Option<i32>,
+    timestamp: Option<String>,
+    changeset: Option<i64>,
+    uid: Option<i32>,
+    user: Option<String>,
+}
+
+// Timestamps are stored as ISO 8601 text, in UTC
+fn format_timestamp(milli_timestamp: i64) -> Option<String> {
+    chrono::DateTime::from_timestamp_millis(milli_timestamp)
+        .map(|timestamp| timestamp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+}
+
+fn info_metadata(info: &osmpbf::Info) -> OsmMetadata {
+    OsmMetadata {
+        version: info.version(),
+        timestamp: info.milli_timestamp().and_then(format_timestamp),
+        changeset: info.changeset(),
+        uid: info.uid(),
+        user: info
+            .user()
+            .and_then(|user| user.ok())
+            .map(|user| user.to_string()),
+    }
+}
+
+fn dense_metadata(info: Option<&osmpbf::DenseNodeInfo>) -> OsmMetadata {
+    info.map(|info| OsmMetadata {
+        version: Some(info.version()),
+        timestamp: format_timestamp(info.milli_timestamp()),
+        changeset: Some(info.changeset()),
+        uid: Some(info.uid()),
+        user: info.user().ok().map(|user| user.to_string()),
+    })
+    .unwrap_or_default()
+}
+
+#[derive(Debug)]
+struct OsmPbf {
+    id: i64,
+    osm_type: OsmType,
+    tags: Vec<(String, String)>,
+    // Only read with `--osm-metadata`
+    metadata: Option<OsmMetadata>,
+    geometry: geo::Geometry<f64>,
+}
+
+fn collect_tags<'a, I>(tags: I) -> Vec<(String, String)>
+where
+    I: Iterator<Item = (&'a str, &'a str)>,
+{
+    tags.map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+// Node locations are buffered by the decoding threads and stored in batches of this size
+const NODE_BATCH_SIZE: usize = 64 * 1024;
+
+// Merge results of the decoding threads, extending the larger one with the smaller one
+fn merge_sets(mut a: HashSet<i64>, mut b: HashSet<i64>) -> HashSet<i64> {
+    if a.len() < b.len() {
+        std::mem::swap(&mut a, &mut b);
+    }
+    a.extend(b);
+    a
+}
+
+// Node locations gathered by the decoding threads. A node on its own is kept as is, so only
+// merging them needs a vector
+enum Nodes {
+    None,
+    One(i64, i32, i32),
+    Many(Vec<(i64, i32, i32)>),
+}
+
+impl Nodes {
+    // Extends the larger vector with the other nodes
+    fn merge(self, other: Nodes) -> Nodes {
+        match (self, other) {
+            (Nodes::None, nodes) | (nodes, Nodes::None) => nodes,
+            (Nodes::One(id, lon, lat), Nodes::One(other_id, other_lon, other_lat)) => {
+                Nodes::Many(vec![(id, lon, lat), (other_id, other_lon, other_lat)])
+            }
+            (Nodes::Many(mut nodes), Nodes::One(id, lon, lat))
+            | (Nodes::One(id, lon, lat), Nodes::Many(mut nodes)) => {
+                nodes.push((id, lon, lat));
+                Nodes::Many(nodes)
+            }
+            (Nodes::Many(mut a), Nodes::Many(mut b)) => {
+                if a.len() < b.len() {
+                    std::mem::swap(&mut a, &mut b);
+                }
+                a.append(&mut b);
+                Nodes::Many(a)
+            }
+        }
+    }
+
+    fn into_vec(self) -> Vec<(i64, i32, i32)> {
+        match self {
+            Nodes::None => Vec::new(),
+            Nodes::One(id, lon, lat) => vec![(id, lon, lat)],
+            Nodes::Many(nodes) => nodes,
+        }
+    }
+}
+
+// Node locations are stored when they make up a way in `needed`, or all of them without
+// it. Tagged nodes are features of their own and emitted as they are read
+fn build_nodes<F>(
+    file_path: &str,
+    filter: &OsmFilter,
+    needed: Option<&HashSet<i64>>,
+    with_metadata: bool,
+    store: &mut dyn NodeStore,
+    emit: F,
+) -> Result<()>
+where
+    F: Fn(OsmPbf) + Sync,
+{
+    let store = Mutex::new(store);
+    let error = Mutex::new(None);
+    let flush = |batch: &mut Vec<(i64, i32, i32)>| {
+        let mut store = store.lock().unwrap();
+        for (id, lon, lat) in batch.drain(..) {
+            if let Err(err) = store.insert(id, lon, lat) {
+                error.lock().unwrap().get_or_insert(err);
+                break;
+            }
+        }
+        batch.clear();
+    };
+    let add_node = |id: i64,
+                    tags: Vec<(String, String)>,
+                    lon: i32,
+                    lat: i32,
+                    metadata: &dyn Fn() -> OsmMetadata| {
+        if !tags.is_empty() && filter.matches(tags.iter().map(|(k, v)| (k.as_str(), v.as_str()))) {
+            emit(OsmPbf {
+                id,
+                osm_type: OsmType::Node,
+                tags,
+                metadata: with_metadata.then(metadata),
+                geometry: Geometry::Point(Point::new(lon as f64 * 1e-7, lat as f64 * 1e-7)),
+            });
+        }
+        if needed.is_none_or(|needed| needed.contains(&id)) {
+            Nodes::One(id, lon, lat)
+        } else {
+            Nodes::None
+        }
+    };
+
+    let reader = ElementReader::from_path(file_path)?;
+    let rest = reader.par_map_reduce(
+        |element| match element {
+            Element::Node(node) => add_node(
+                node.id(),
+                collect_tags(node.tags()),
+                node.decimicro_lon(),
+                node.decimicro_lat(),
+                &|| info_metadata(&node.info()),
+            ),
+            Element::DenseNode(dense_node) => add_node(
+                dense_node.id(),
+                collect_tags(dense_node.tags()),
+                dense_node.decimicro_lon(),
+                dense_node.decimicro_lat(),
+                &|| dense_metadata(dense_node.info()),
+            ),
+            _ => Nodes::None,
+        },
+        || Nodes::None,
+        |a, b| match a.merge(b) {
+            Nodes::Many(mut nodes) if nodes.len() >= NODE_BATCH_SIZE => {
+                flush(&mut nodes);
+                Nodes::Many(nodes)
+            }
+            nodes => nodes,
+        },
+    )?;
+    flush(&mut rest.into_vec());
+    if let Some(err) = error.into_inner().unwrap() {
+        return Err(err);
+    }
+
+    store.into_inner().unwrap().finish()
+}
+
+// Nodes making up the ways that are kept, or that relations kept are made of
+fn needed_nodes(
+    file_path: &str,
+    filter: &OsmFilter,
+    relation_ways: &HashSet<i64>,
+) -> Result<HashSet<i64>> {
+    let reader = ElementReader::from_path(file_path)?;
+    let node_ids = reader.par_map_reduce(
+        |element| match element {
+            Element::Way(way)
+                if relation_ways.contains(&way.id()) || filter.matches(way.tags()) =>
+            {
+                way.refs().collect()
+            }
+            _ => HashSet::new(),
+        },
+        HashSet::new,
+        merge_sets,
+    )?;
+    Ok(node_ids)
+}
+
+// None when a node is missing from the store, e.g. cut off by an extract
+fn way_coords(way: &osmpbf::Way, nodes: &dyn NodeStore) -> Option<Vec<Coord<f64>>> {
+    way.refs().map(|node_id| nodes.get(node_id)).collect()
+}
+
+fn build_line(way: &osmpbf::Way, nodes: &dyn NodeStore, with_metadata: bool) -> Option<OsmPbf> {
+    let points = way_coords(way, nodes).filter(|points| points.len() >= 2)?;
+    Some(OsmPbf {
+        id: way.id(),
+        osm_type: OsmType::Way,
+        tags: collect_tags(way.tags()),
+        metadata: with_metadata.then(|| info_metadata(&way.info())),
+        geometry: Geometry::LineString(LineString::from(points)),
+    })
+}
+
+fn build_polygon(way: &osmpbf::Way, nodes: &dyn NodeStore, with_metadata: bool) -> Option<OsmPbf> {
+    let points = way_coords(way, nodes).filter(|points| points.len() >= 4)?;
+    Some(OsmPbf {
+        id: way.id(),
+        osm_type: OsmType::Way,
+        tags: collect_tags(way.tags()),
+        metadata: with_metadata.then(|| info_metadata(&way.info())),
+        geometry: Geometry::Polygon(Polygon::new(LineString::from(points), vec![])),
+    })
+}
+
+// Relations describing an area made of the ways they reference
+fn is_area_relation(relation: &osmpbf::Relation) -> bool {
+    relation
+        .tags()
+        .any(|(key, value)| key == "type" && (value == "multipolygon" || value == "boundary"))
+}
+
+// Relations come after ways in a file, so the ways they reference are found beforehand
+// and kept while reading ways
+fn relation_member_ways(file_path: &str, filter: &OsmFilter) -> Result<HashSet<i64>> {
+    let reader = ElementReader::from_path(file_path)?;
+    let way_ids = reader.par_map_reduce(
+        |element| match element {
+            Element::Relation(relation)
+                if is_area_relation(&relation) && filter.matches(relation.tags()) =>
+            {
+                relation
+                    .members()
+                    .filter(|member| member.member_type == RelMemberType::Way)
+                    .map(|member| member.member_id)
+                    .collect()
+            }
+            _ => HashSet::new(),
+        },
+        HashSet::new,
+        merge_sets,
+    )?;
+    Ok(way_ids)
+}
+
+// Area relation kept until every way it is made of has been read
+struct AreaRelation {
+    id: i64,
+    tags: Vec<(String, String)>,
+    metadata: Option<OsmMetadata>,
+    ways: Vec<i64>,
+}
+
+// What the decoding threads gather while reading ways and relations
+#[derive(Default)]
+struct WayPass {
+    member_ways: HashMap<i64, Vec<Coord<f64>>>,
+    relations: Vec<AreaRelation>,
+    skipped_ways: usize,
+}
+
+impl WayPass {
+    fn merge(mut self, mut other: WayPass) -> WayPass {
+        if self.member_ways.len() < other.member_ways.len() {
+            std::mem::swap(&mut self.member_ways, &mut other.member_ways);
+        }
+        self.member_ways.extend(other.member_ways);
+        self.relations.append(&mut other.relations);
+        self.skipped_ways += other.skipped_ways;
+        self
+    }
+}
+
+fn build_multipolygon(
+    relation: AreaRelation,
+    member_ways: &HashMap<i64, Vec<Coord<f64>>>,
+) -> Option<OsmPbf> {
+    let mut ways: Vec<Vec<Coord<f64>>> = Vec::new();
+    for way_id in relation.ways.iter() {
+        // Members missing from the file, e.g. cut off by an extract, leave the area incomplete
+        ways.push(member_ways.get(way_id)?.clone());
+    }
+    let multipolygon = assemble_multipolygon(ways)?;
+    Some(OsmPbf {
+        id: relation.id,
+        osm_type: OsmType::Relation,
+        tags: relation.tags,
+        metadata: relation.metadata,
+        geometry: Geometry::MultiPolygon(multipolygon),
+    })
+}
+
+// Ways are emitted as they are read, area relations once every way has been read
+fn build_polys_and_lines<F>(
+    file_path: &str,
+    nodes: &dyn NodeStore,
+    relation_ways: &HashSet<i64>,
+    area_rules: &AreaRules,
+    filter: &OsmFilter,
+    with_metadata: bool,
+    emit: F,
+) -> Result<()>
+where
+    F: Fn(OsmPbf) + Sync,
+{
+    let reader = ElementReader::from_path(file_path)?;
+    let way_pass = reader.par_map_reduce(
+        |element| {
+            let mut way_pass = WayPass::default();
+            match element {
+                Element::Way(way) => {
+                    // A member way left out leaves its relations incomplete, they are counted there
+                    if relation_ways.contains(&way.id()) {
+                        if let Some(coords) = way_coords(&way, nodes) {
+                            way_pass.member_ways.insert(way.id(), coords);
+                        }
+                    }
+                    if !filter.matches(way.tags()) {
+                        return way_pass;
+                    }
+                    // A closed way is a polygon when its tags describe an area. Closing takes
+                    // at least 4 refs, an empty way has no first or last to compare
+                    let item = if way.refs().len() >= 4
+                        && way.refs().next() == way.refs().last()
+                        && area_rules.is_area(way.tags())
+                    {
+                        build_polygon(&way, nodes, with_metadata)
+                    } else {
+                        build_line(&way, nodes, with_metadata)
+                    };
+                    match item {
+                        Some(item) => emit(item),
+                        None => way_pass.skipped_ways += 1,
+                    }
+                }
+                Element::Relation(relation)
+                    if is_area_relation(&relation) && filter.matches(relation.tags()) =>
+                {
+                    way_pass.relations.push(AreaRelation {
+                        id: relation.id(),
+                        tags: collect_tags(relation.tags()),
+                        metadata: with_metadata.then(|| info_metadata(&relation.info())),
+                        ways: relation
+                            .members()
+                            .filter(|member| member.member_type == RelMemberType::Way)
+                            .map(|member| member.member_id)
+                            .collect(),
+                    });
+                }
+                _ => {}
+            }
+            way_pass
+        },
+        WayPass::default,
+        WayPass::merge,
+    )?;
+
+    if way_pass.skipped_ways > 0 {
+        println!(
+            "❌ {} ways with missing nodes or too few nodes, skipped",
+            way_pass.skipped_ways
+        );
+    }
+
+    let mut skipped = 0;
+    for relation in way_pass.relations {
+        match build_multipolygon(relation, &way_pass.member_ways) {
+            Some(item) => emit(item),
+            None => skipped += 1,
+        }
+    }
+    if skipped > 0 {
+        println!(
+            "❌ {} multipolygon relations could not be assembled, skipped",
+            skipped
+        );
+    }
+
+    Ok(())
+}
+
+fn osmpbf_to_row(
+    item: OsmPbf,
+    tag_storage: TagStorage,
+    columns: &[String],
+    with_metadata: bool,
+) -> Row {
+    let mut row = Row::new();
+    row.add(AcceptedTypes::BigInt(Some(item.id)));
+    row.add(AcceptedTypes::Text(Some(item.osm_type.code().to_string())));
+    if with_metadata {
+        let metadata = item.metadata.unwrap_or_default();
+        row.add(AcceptedTypes::Int(metadata.version));
+        row.add(AcceptedTypes::Text(metadata.timestamp));
+        row.add(AcceptedTypes::BigInt(metadata.changeset));
+        row.add(AcceptedTypes::Int(metadata.uid));
+        row.add(AcceptedTypes::Text(metadata.user));
+    }
+    // Promoted tags
+    for column in columns.iter() {
+        let value = item
+            .tags
+            .iter()
+            .find(|(key, _)| key == column)
+            .map(|(_, value)| value.clone());
+        row.add(AcceptedTypes::Text(value));
+    }
+    // Tags
+    let tags = match tag_storage {
+        TagStorage::Array => AcceptedTypes::Array(Some(
+            item.tags
+                .into_iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect(),
+        )),
+        TagStorage::Jsonb => AcceptedTypes::Json(Some(
+            item.tags
+                .into_iter()
+                .map(|(key, value)| (key, value.into()))
+                .collect::<serde_json::Map<String, serde_json::Value>>()
+                .into(),
+        )),
+        TagStorage::Hstore => AcceptedTypes::Hstore(Some(
+            item.tags
+                .into_iter()
+                .map(|(key, value)| (key, Some(value)))
+                .collect(),
+        )),
+    };
+    row.add(tags);
+    // Geom
+    let wkb = geom_to_wkb(&item.geometry).expect("❌ Could not convert geometry to WKB");
+    row.add(AcceptedTypes::Geometry(Some(Wkb { geometry: wkb })));
+    row
+}
+
+// Id and type of the element come first, then its metadata when asked for, promoted tags
+// as text columns and the tags column
+pub fn determine_data_types(
+    tag_storage: TagStorage,
+    columns: &[String],
+    with_metadata: bool,
+) -> Result<Vec<NewTableTypes>> {
+    let mut data_types = vec![
+        NewTableTypes {
+            column_name: ID_COLUMNS[0].to_string(),
+            data_type: Type::INT8,
+        },
+        NewTableTypes {
+            column_name: ID_COLUMNS[1].to_string(),
+            data_type: Type::TEXT,
+        },
+    ];
+    if with_metadata {
+        let metadata_types = [Type::INT4, Type::TEXT, Type::INT8, Type::INT4, Type::TEXT];
+        for (column, data_type) in METADATA_COLUMNS.iter().zip(metadata_types) {
+            data_types.push(NewTableTypes {
+                column_name: column.to_string(),
+                data_type,
+            });
+        }
+    }
+    data_types.extend(columns.iter().map(|column| NewTableTypes {
+        column_name: column.clone(),
+        data_type: Type::TEXT,
+    }));
+    data_types.push(NewTableTypes {
+        column_name: "tags".to_string(),
+        data_type: match tag_storage {
+            TagStorage::Array => Type::TEXT_ARRAY,
+            TagStorage::Jsonb => Type::JSONB,
+            TagStorage::Hstore => hstore_type(),
+        },
+    });
+
+    Ok(data_types)
+}
+
+pub fn read_osmpbf(args: &Cli) -> Result<RowStream> {
+    let args = args.clone();
+    Ok(stream_rows(move |sender| {
+        // Definitions PROJ can't read fail here, before any thread needs them
+        transformer(&args)?;
+        // Features are turned into rows, and reprojected, while the file is still decoded
+        let items = decode_osmpbf(&args);
+        convert_in_parallel(
+            items.into_iter(),
+            args.threads,
+            sender,
+            || transformer(&args),
+            |proj, mut item: OsmPbf| {
+                if let Some(proj) = proj {
+                    item.geometry.transform(proj)?;
+                }
+                Ok(osmpbf_to_row(
+                    item,
+                    args.tags,
+                    &args.columns,
+                    args.osm_metadata,
+                ))
+            },
+        )
+    }))
+}
+
+// Features in the order they are decoded, tagged nodes first
+fn decode_osmpbf(args: &Cli) -> Receiver<Result<OsmPbf>> {
+    let args = args.clone();
+    stream_rows(move |sender| {
+        // Blocks are decoded by a pool of threads, all cores unless told otherwise
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(args.threads.unwrap_or(0))
+            .build()?;
+        pool.install(|| {
+            let filter = OsmFilter::parse(&args.osm_filter)?;
+            let relation_ways = relation_member_ways(&args.input, &filter)?;
+            // With a filter, only the nodes of the ways kept are worth holding in memory
+            let needed = if filter.is_empty() {
+                None
+            } else {
+                Some(needed_nodes(&args.input, &filter, &relation_ways)?)
+            };
+            // Sending only fails once the writer has gone away, nothing left to do then
+            let sent = AtomicBool::new(true);
+            let emit = |item| {
+                if sender.send(Ok(item)).is_err() {
+                    sent.store(false, Ordering::Relaxed);
+                }
+            };
+            let mut nodes = create_node_store(args.node_cache, args.node_cache_file.as_deref())?;
+            build_nodes(
+                &args.input,
+                &filter,
+                needed.as_ref(),
+                args.osm_metadata,
+                nodes.as_mut(),
+                emit,
+            )?;
+            println!("{} node locations cached", nodes.len());
+            if !sent.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let area_rules = AreaRules::new(&args.area_keys, &args.linear_keys);
+            build_polys_and_lines(
+                &args.input,
+                nodes.as_ref(),
+                &relation_ways,
+                &area_rules,
+                &filter,
+                args.osm_metadata,
+                emit,
+            )
+        })
+    })
+}
+
+// Ids of ways or relations along with those of their members, in file order
+pub type MembersStream = Receiver<Result<(i64, Vec<i64>)>>;
+
+// Nodes of the ways kept, or that relations kept are made of, so ways can be rebuilt
+// when changes are applied later on
+pub fn read_way_nodes(args: &Cli) -> Result<MembersStream> {
+    let args = args.clone();
+    Ok(stream_rows(move |sender| {
+        let filter = OsmFilter::parse(&args.osm_filter)?;
+        let relation_ways = relation_member_ways(&args.input, &filter)?;
+        let reader = ElementReader::from_path(&args.input)?;
+        reader.for_each(|element| {
+            if let Element::Way(way) = element {
+                if relation_ways.contains(&way.id()) || filter.matches(way.tags()) {
+                    // Sending only fails once the writer has gone away
+                    let _ = sender.send(Ok((way.id(), way.refs().collect())));
+                }
+            }
+        })?;
+        Ok(())
+    }))
+}
+
+// Ways of the area relations kept, so relations can be rebuilt when their ways change
+pub fn read_relation_ways(args: &Cli) -> Result<MembersStream> {
+    let args = args.clone();
+    Ok(stream_rows(move |sender| {
+        let filter = OsmFilter::parse(&args.osm_filter)?;
+        let reader = ElementReader::from_path(&args.input)?;
+        reader.for_each(|element| {
+            if let Element::Relation(relation) = element {
+                if is_area_relation(&relation) && filter.matches(relation.tags()) {
+                    let ways = relation
+                        .members()
+                        .filter(|member| member.member_type == RelMemberType::Way)
+                        .map(|member| member.member_id)
+                        .collect();
+                    // Sending only fails once the writer has gone away
+                    let _ = sender.send(Ok((relation.id(), ways)));
+                }
+            }
+        })?;
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn monaco_nodes(node_cache: NodeCache) -> Box<dyn NodeStore> {
+        let mut nodes = create_node_store(node_cache, None).unwrap();
+        build_nodes(
+            "examples/osmpbf/monaco-latest.osm.pbf",
+            &OsmFilter::default(),
+            None,
+            false,
+            nodes.as_mut(),
+            |_| {},
+        )
+        .unwrap();
+        nodes
+    }
+
+    #[test]
+    fn test_build_nodes() {
+        for node_cache in [NodeCache::Sparse, NodeCache::Dense, NodeCache::File] {
+            let nodes = monaco_nodes(node_cache);
+            assert_eq!(nodes.len(), 30798);
+        }
+    }
+
+    #[test]
+    fn test_build_polys_and_lines() {
+        let nodes = monaco_nodes(NodeCache::Sparse);
+        let count = AtomicUsize::new(0);
+        let polygons = AtomicUsize::new(0);
+        let multipolygons = AtomicUsize::new(0);
+        let filter = OsmFilter::default();
+        let relation_ways =
+            relation_member_ways("examples/osmpbf/monaco-latest.osm.pbf", &filter).unwrap();
+        build_polys_and_lines(
+            "examples/osmpbf/monaco-latest.osm.pbf",
+            nodes.as_ref(),
+            &relation_ways,
+            &AreaRules::default(),
+            &filter,
+            false,
+            |item| {
+                count.fetch_add(1, Ordering::Relaxed);
+                match item.geometry {
+                    Geometry::Polygon(_) => polygons.fetch_add(1, Ordering::Relaxed),
+                    Geometry::MultiPolygon(_) => multipolygons.fetch_add(1, Ordering::Relaxed),
+                    _ => 0,
+                };
+            },
+        )
+        .unwrap();
+        assert_eq!(count.into_inner(), 4988);
+        assert_eq!(polygons.into_inner(), 1716);
+        assert_eq!(multipolygons.into_inner(), 44);
+    }
+
+    #[test]
+    fn test_build_polys_and_lines_missing_nodes() {
+        // Ways whose nodes are all missing are skipped rather than emitted empty
+        let mut nodes = create_node_store(NodeCache::Sparse, None).unwrap();
+        nodes.finish().unwrap();
+        let count = AtomicUsize::new(0);
+        build_polys_and_lines(
+            "examples/osmpbf/monaco-latest.osm.pbf",
+            nodes.as_ref(),
+            &HashSet::new(),
+            &AreaRules::default(),
+            &OsmFilter::default(),
+            false,
+            |_| {
+                count.fetch_add(1, Ordering::Relaxed);
+            },
+        )
+        .unwrap();
+        assert_eq!(count.into_inner(), 0);
+    }
+
+    #[test]
+    fn test_read_osmpbf_filtered() {
+        let args = Cli {
+            input: "examples/osmpbf/monaco-latest.osm.pbf".to_string(),
+            srid: Some(4326),
+            uri: "postgresql://localhost:5432/postgis".to_string(),
+            table: "monaco".to_string(),
+            osm_filter: vec!["building=*".to_string(), "!building=yes".to_string()],
+            ..Default::default()
+        };
+        let rows = read_osmpbf(&args).unwrap();
+        let rows = rows.iter().collect::<Result<Vec<Row>>>().unwrap();
+        for row in rows.iter() {
+            let AcceptedTypes::Array(Some(tags)) = &row.columns[2] else {
+                panic!("❌ Tags not found");
+            };
+            assert!(tags.iter().any(|tag| tag.starts_with("building=")));
+            assert!(!tags.contains(&"building=yes".to_string()));
+        }
+        assert_eq!(rows.len(), 633);
+    }
+
+    #[test]
+    fn test_osmpbf_to_row() {
+        let item = || OsmPbf {
+            id: 42,
+            osm_type: OsmType::Way,
+            tags: vec![
+                ("highway".to_string(), "primary".to_string()),
+                ("name".to_string(), "Boulevard Albert 1er".to_string()),
+            ],
+            metadata: None,
+            geometry: Geometry::Point(Point::new(7.42, 43.73)),
+        };
+        let columns = vec!["name".to_string(), "building".to_string()];
+        let data_types = determine_data_types(TagStorage::Jsonb, &columns, false).unwrap();
+        assert_eq!(data_types.len(), 5);
+        assert_eq!(data_types[0].column_name, "osm_id");
+        assert_eq!(data_types[4].data_type, Type::JSONB);
+
+        let row = osmpbf_to_row(item(), TagStorage::Jsonb, &columns, false);
+        assert_eq!(row.columns.len(), 6);
+        assert!(matches!(row.columns[0], AcceptedTypes::BigInt(Some(42))));
+        assert!(matches!(&row.columns[1], AcceptedTypes::Text(Some(osm_type)) if osm_type == "W"));
+        assert!(
+            matches!(&row.columns[2], AcceptedTypes::Text(Some(name)) if name == "Boulevard Albert 1er")
+        );
+        assert!(matches!(row.columns[3], AcceptedTypes::Text(None)));
+        assert!(
+            matches!(&row.columns[4], AcceptedTypes::Json(Some(tags)) if tags["highway"] == "primary")
+        );
+
+        let row = osmpbf_to_row(item(), TagStorage::Hstore, &[], false);
+        assert!(matches!(&row.columns[2], AcceptedTypes::Hstore(Some(tags)) if tags.len() == 2));
+
+        // Metadata missing from the file is stored as NULL
+        let data_types = determine_data_types(TagStorage::Array, &[], true).unwrap();
+        let row = osmpbf_to_row(item(), TagStorage::Array, &[], true);
+        assert_eq!(data_types.len() + 1, row.columns.len());
+        assert!(matches!(row.columns[2], AcceptedTypes::Int(None)));
+    }
+
+    #[test]
+    fn test_read_osmpbf_metadata() {
+        let args = Cli {
+            input: "examples/osmpbf/monaco-latest.osm.pbf".to_string(),
+            srid: Some(4326),
+            uri: "postgresql://localhost:5432/postgis".to_string(),
+            table: "monaco".to_string(),
+            osm_filter: vec!["building=*".to_string()],
+            osm_metadata: true,
+            ..Default::default()
+        };
+        let rows = read_osmpbf(&args).unwrap();
+        let rows = rows.iter().collect::<Result<Vec<Row>>>().unwrap();
+        assert!(!rows.is_empty());
+        for row in rows.iter() {
+            assert!(matches!(row.columns[0], AcceptedTypes::BigInt(Some(id)) if id > 0));
+            assert!(matches!(row.columns[2], AcceptedTypes::Int(Some(version)) if version > 0));
+            assert!(
+                matches!(&row.columns[3], AcceptedTypes::Text(Some(timestamp)) if timestamp.ends_with('Z'))
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_osmpbf() {
+        let args = Cli {
+            input: "examples/osmpbf/monaco-latest.osm.pbf".to_string(),
+            srid: Some(4326),
+            uri: "postgresql://localhost:5432/postgis".to_string(),
+            table: "monaco".to_string(),
+            ..Default::default()
+        };
+        let rows = read_osmpbf(&args).unwrap();
+        let rows = rows.iter().collect::<Result<Vec<Row>>>().unwrap();
+        // Tagged nodes along with ways and relations
+        assert_eq!(rows.len(), 8245);
+    }
+
+    #[test]
+    fn test_read_relation_ways() {
+        let args = Cli {
+            input: "examples/osmpbf/monaco-latest.osm.pbf".to_string(),
+            ..Default::default()
+        };
+        let relations = read_relation_ways(&args).unwrap();
+        let relations = relations.iter().collect::<Result<Vec<_>>>().unwrap();
+        // The ways relations are made of are those kept for them while reading ways
+        let relation_ways = relation_member_ways(&args.input, &OsmFilter::default()).unwrap();
+        let ways: HashSet<i64> = relations.into_iter().flat_map(|(_, ways)| ways).collect();
+        assert!(!ways.is_empty());
+        assert_eq!(ways, relation_ways);
+    }
+}
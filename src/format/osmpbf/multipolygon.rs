@@ -0,0 +1,118 @@
+use geo::{Area, Contains, Coord, LineString, MultiPolygon, Polygon};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+// Join ways end to end into closed rings. Ways that can't be closed are dropped
+fn stitch_rings(mut ways: Vec<Vec<Coord<f64>>>) -> Vec<LineString<f64>> {
+    ways.retain(|way| way.len() >= 2);
+    let mut rings: Vec<LineString<f64>> = Vec::new();
+    while let Some(mut ring) = ways.pop() {
+        // Keep appending the way that continues the ring until it's closed
+        while ring.first() != ring.last() {
+            let end = ring[ring.len() - 1];
+            let Some(index) = ways
+                .iter()
+                .position(|way| way[0] == end || way[way.len() - 1] == end)
+            else {
+                break;
+            };
+            let mut way = ways.swap_remove(index);
+            if way[0] != end {
+                way.reverse();
+            }
+            ring.extend(way.into_iter().skip(1));
+        }
+        if ring.len() >= 4 && ring.first() == ring.last() {
+            rings.push(LineString::from(ring));
+        }
+    }
+    rings
+}
+
+// Rings of a valid multipolygon don't cross, so any vertex inside means the whole ring is
+fn contains_ring(outer: &Polygon<f64>, inner: &Polygon<f64>) -> bool {
+    inner
+        .exterior()
+        .points()
+        .any(|point| outer.contains(&point))
+}
+
+// Assemble the member ways of a relation into a multipolygon. Roles are often wrong, so
+// rings are classified by how deep they are nested: rings inside an odd number of rings are
+// holes of the ring directly containing them, the rest are outers
+pub fn assemble_multipolygon(ways: Vec<Vec<Coord<f64>>>) -> Option<MultiPolygon<f64>> {
+    let mut rings: Vec<Polygon<f64>> = stitch_rings(ways)
+        .into_iter()
+        .map(|ring| Polygon::new(ring, vec![]))
+        .collect();
+    if rings.is_empty() {
+        return None;
+    }
+
+    // Largest first, a ring can then only be contained by rings before it
+    rings.sort_by(|a, b| {
+        b.unsigned_area()
+            .partial_cmp(&a.unsigned_area())
+            .unwrap_or(Ordering::Equal)
+    });
+    let mut parents: Vec<Option<usize>> = vec![None; rings.len()];
+    let mut depths: Vec<usize> = vec![0; rings.len()];
+    for index in 0..rings.len() {
+        // Smallest ring containing this one
+        if let Some(parent) = (0..index)
+            .rev()
+            .find(|parent| contains_ring(&rings[*parent], &rings[index]))
+        {
+            parents[index] = Some(parent);
+            depths[index] = depths[parent] + 1;
+        }
+    }
+
+    let mut polygons: Vec<Polygon<f64>> = Vec::new();
+    let mut outers: HashMap<usize, usize> = HashMap::new();
+    for (index, ring) in rings.into_iter().enumerate() {
+        match parents[index] {
+            Some(parent) if depths[index] % 2 == 1 => {
+                let (exterior, _) = ring.into_inner();
+                polygons[outers[&parent]].interiors_push(exterior);
+            }
+            _ => {
+                outers.insert(index, polygons.len());
+                polygons.push(ring);
+            }
+        }
+    }
+
+    Some(MultiPolygon::new(polygons))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coords(points: &[(f64, f64)]) -> Vec<Coord<f64>> {
+        points.iter().map(|&(x, y)| Coord { x, y }).collect()
+    }
+
+    #[test]
+    fn test_assemble_multipolygon() {
+        let ways = vec![
+            // Outer ring split in two ways, one of them reversed
+            coords(&[(0.0, 0.0), (10.0, 0.0), (10.0, 10.0)]),
+            coords(&[(0.0, 0.0), (0.0, 10.0), (10.0, 10.0)]),
+            // Hole
+            coords(&[(2.0, 2.0), (4.0, 2.0), (4.0, 4.0), (2.0, 4.0), (2.0, 2.0)]),
+            // Island in the hole
+            coords(&[(2.5, 2.5), (3.5, 2.5), (3.5, 3.5), (2.5, 2.5)]),
+            // Separate outer
+            coords(&[(20.0, 0.0), (21.0, 0.0), (21.0, 1.0), (20.0, 0.0)]),
+            // Unclosed, dropped
+            coords(&[(30.0, 0.0), (31.0, 0.0)]),
+        ];
+        let multipolygon = assemble_multipolygon(ways).unwrap();
+        assert_eq!(multipolygon.0.len(), 3);
+        assert_eq!(multipolygon.0[0].interiors().len(), 1);
+        assert_eq!(multipolygon.0[0].unsigned_area(), 96.0);
+        assert!(assemble_multipolygon(vec![coords(&[(0.0, 0.0), (1.0, 0.0)])]).is_none());
+    }
+}
@@ -0,0 +1,310 @@
+use crate::{Error, Result};
+
+use geo::Coord;
+use memmap2::MmapMut;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::path::PathBuf;
+
+// Where node locations are kept while ways are assembled
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum NodeCache {
+    // Sorted vector of the nodes found, best for extracts
+    #[default]
+    Sparse,
+    // Arrays indexed by id, best when most ids in a range are used, e.g. the planet
+    Dense,
+    // Array indexed by id in a memory-mapped file
+    File,
+}
+
+// Shared by the threads decoding the file
+pub trait NodeStore: Send + Sync {
+    // Longitude and latitude in decimicro degrees
+    fn insert(&mut self, id: i64, lon: i32, lat: i32) -> Result<()>;
+    fn get(&self, id: i64) -> Option<Coord<f64>>;
+    fn remove(&mut self, id: i64);
+    fn len(&self) -> usize;
+    // Called once every node is inserted
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub fn create_node_store(
+    node_cache: NodeCache,
+    file_path: Option<&str>,
+) -> Result<Box<dyn NodeStore>> {
+    Ok(match node_cache {
+        NodeCache::Sparse => Box::new(SparseStore::default()),
+        NodeCache::Dense => Box::new(DenseStore::default()),
+        NodeCache::File => Box::new(FileStore::new(file_path)?),
+    })
+}
+
+// Node locations persisted by an earlier import, to apply changes to
+pub fn open_node_store(file_path: &str) -> Result<Box<dyn NodeStore>> {
+    Ok(Box::new(FileStore::open(file_path)?))
+}
+
+// Longitude and latitude packed in a u64. Flipping the sign bit makes 0, the value of
+// unset entries, decode to i32::MIN which is never a valid coordinate
+fn pack(lon: i32, lat: i32) -> u64 {
+    (((lon ^ i32::MIN) as u32 as u64) << 32) | ((lat ^ i32::MIN) as u32 as u64)
+}
+
+fn unpack(packed: u64) -> Option<Coord<f64>> {
+    if packed == 0 {
+        return None;
+    }
+    let lon = ((packed >> 32) as u32 as i32) ^ i32::MIN;
+    let lat = (packed as u32 as i32) ^ i32::MIN;
+    Some(Coord {
+        x: lon as f64 * 1e-7,
+        y: lat as f64 * 1e-7,
+    })
+}
+
+#[derive(Default)]
+pub struct SparseStore {
+    nodes: Vec<(i64, u64)>,
+    // Set by any insert out of order, nodes are then sorted by finish
+    unsorted: bool,
+}
+
+impl NodeStore for SparseStore {
+    fn insert(&mut self, id: i64, lon: i32, lat: i32) -> Result<()> {
+        if self.nodes.last().is_some_and(|(last, _)| *last >= id) {
+            self.unsorted = true;
+        }
+        self.nodes.push((id, pack(lon, lat)));
+        Ok(())
+    }
+    fn get(&self, id: i64) -> Option<Coord<f64>> {
+        debug_assert!(!self.unsorted, "nodes looked up before finish");
+        let index = self.nodes.binary_search_by_key(&id, |(id, _)| *id).ok()?;
+        unpack(self.nodes[index].1)
+    }
+    fn remove(&mut self, id: i64) {
+        if let Ok(index) = self.nodes.binary_search_by_key(&id, |(id, _)| *id) {
+            self.nodes.remove(index);
+        }
+    }
+    fn len(&self) -> usize {
+        self.nodes.len()
+    }
+    // Files are usually sorted by id already, only sort those that aren't
+    fn finish(&mut self) -> Result<()> {
+        if self.unsorted {
+            self.nodes.sort_unstable_by_key(|(id, _)| *id);
+            self.unsorted = false;
+        }
+        self.nodes.shrink_to_fit();
+        Ok(())
+    }
+}
+
+// Number of nodes in each block of the dense store
+const BLOCK_SIZE: i64 = 1024;
+
+// Blocks are only allocated once one of their nodes is found
+#[derive(Default)]
+pub struct DenseStore {
+    blocks: HashMap<i64, Box<[u64]>>,
+    len: usize,
+}
+
+impl NodeStore for DenseStore {
+    fn insert(&mut self, id: i64, lon: i32, lat: i32) -> Result<()> {
+        let block = self
+            .blocks
+            .entry(id.div_euclid(BLOCK_SIZE))
+            .or_insert_with(|| vec![0; BLOCK_SIZE as usize].into_boxed_slice());
+        let entry = &mut block[id.rem_euclid(BLOCK_SIZE) as usize];
+        if *entry == 0 {
+            self.len += 1;
+        }
+        *entry = pack(lon, lat);
+        Ok(())
+    }
+    fn get(&self, id: i64) -> Option<Coord<f64>> {
+        let block = self.blocks.get(&id.div_euclid(BLOCK_SIZE))?;
+        unpack(block[id.rem_euclid(BLOCK_SIZE) as usize])
+    }
+    fn remove(&mut self, id: i64) {
+        if let Some(block) = self.blocks.get_mut(&id.div_euclid(BLOCK_SIZE)) {
+            let entry = &mut block[id.rem_euclid(BLOCK_SIZE) as usize];
+            if *entry != 0 {
+                self.len -= 1;
+                *entry = 0;
+            }
+        }
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+// Entries the file starts with, it doubles whenever a larger id is found
+const INITIAL_FILE_ENTRIES: u64 = 1 << 20;
+
+// Array of packed locations indexed by id, in a sparse file so unused ids take no disk space
+pub struct FileStore {
+    path: PathBuf,
+    temporary: bool,
+    file: File,
+    map: MmapMut,
+    len: usize,
+}
+
+impl FileStore {
+    // Without a path the file is created in the temporary directory and removed afterwards
+    pub fn new(file_path: Option<&str>) -> Result<Self> {
+        let (path, temporary) = match file_path {
+            Some(file_path) => (PathBuf::from(file_path), false),
+            None => (
+                std::env::temp_dir().join(format!("popgis-nodes-{}.bin", std::process::id())),
+                true,
+            ),
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        file.set_len(INITIAL_FILE_ENTRIES * 8)?;
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        Ok(FileStore {
+            path,
+            temporary,
+            file,
+            map,
+            len: 0,
+        })
+    }
+
+    // Reopen a file kept by an earlier import, `len` then counts the nodes inserted since
+    pub fn open(file_path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(file_path)
+            .map_err(|_| {
+                Error::FailedValidation(format!("❌ Node cache file '{}' not found", file_path))
+            })?;
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        Ok(FileStore {
+            path: PathBuf::from(file_path),
+            temporary: false,
+            file,
+            map,
+            len: 0,
+        })
+    }
+
+    fn entries(&self) -> u64 {
+        self.map.len() as u64 / 8
+    }
+}
+
+impl NodeStore for FileStore {
+    fn insert(&mut self, id: i64, lon: i32, lat: i32) -> Result<()> {
+        // Negative ids only appear in files that were never uploaded
+        let Ok(index) = u64::try_from(id) else {
+            return Ok(());
+        };
+        if index >= self.entries() {
+            let entries = (index + 1).max(self.entries() * 2);
+            self.file.set_len(entries * 8)?;
+            self.map = unsafe { MmapMut::map_mut(&self.file)? };
+        }
+        let offset = index as usize * 8;
+        let entry = &mut self.map[offset..offset + 8];
+        if entry.iter().all(|byte| *byte == 0) {
+            self.len += 1;
+        }
+        entry.copy_from_slice(&pack(lon, lat).to_le_bytes());
+        Ok(())
+    }
+    fn get(&self, id: i64) -> Option<Coord<f64>> {
+        let index = u64::try_from(id).ok()?;
+        if index >= self.entries() {
+            return None;
+        }
+        let offset = index as usize * 8;
+        let entry: [u8; 8] = self.map[offset..offset + 8].try_into().ok()?;
+        unpack(u64::from_le_bytes(entry))
+    }
+    fn remove(&mut self, id: i64) {
+        if let Ok(index) = u64::try_from(id) {
+            if index < self.entries() {
+                let offset = index as usize * 8;
+                let entry = &mut self.map[offset..offset + 8];
+                if entry.iter().any(|byte| *byte != 0) {
+                    self.len = self.len.saturating_sub(1);
+                    entry.fill(0);
+                }
+            }
+        }
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn finish(&mut self) -> Result<()> {
+        self.map.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_store(mut store: Box<dyn NodeStore>) {
+        // Out of order, with a node on null island and one at the antimeridian
+        store.insert(5_000_000, 74_200_000, 437_300_000).unwrap();
+        store.insert(3, 0, 0).unwrap();
+        store.insert(2_000, -1_800_000_000, -900_000_000).unwrap();
+        store.finish().unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.get(3), Some(Coord { x: 0.0, y: 0.0 }));
+        let coord = store.get(5_000_000).unwrap();
+        assert!((coord.x - 7.42).abs() < 1e-9 && (coord.y - 43.73).abs() < 1e-9);
+        let coord = store.get(2_000).unwrap();
+        assert!((coord.x + 180.0).abs() < 1e-9 && (coord.y + 90.0).abs() < 1e-9);
+        assert_eq!(store.get(4), None);
+        assert_eq!(store.get(10_000_000), None);
+        store.remove(3);
+        assert_eq!(store.get(3), None);
+
+        // Nodes inserted once the store is built are found after another finish
+        store.insert(1, 10, 20).unwrap();
+        store.finish().unwrap();
+        assert!(store.get(1).is_some());
+        assert!(store.get(2_000).is_some());
+        assert_eq!(store.len(), 3);
+    }
+
+    #[test]
+    fn test_node_stores() {
+        check_store(create_node_store(NodeCache::Sparse, None).unwrap());
+        check_store(create_node_store(NodeCache::Dense, None).unwrap());
+        let file_path = std::env::temp_dir().join("popgis_test_nodes.bin");
+        check_store(create_node_store(NodeCache::File, file_path.to_str()).unwrap());
+
+        // Locations are still there once the file is reopened
+        let store = open_node_store(file_path.to_str().unwrap()).unwrap();
+        assert_eq!(store.get(3), None);
+        assert!(store.get(2_000).is_some());
+        std::fs::remove_file(file_path).unwrap();
+    }
+}
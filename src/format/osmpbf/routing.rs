@@ -0,0 +1,353 @@
+use crate::format::common::{stream_rows, AcceptedTypes, NewTableTypes, Row, RowStream};
+use crate::pg::binary_copy::Wkb;
+use crate::utils::cli::Cli;
+use crate::{Error, Result};
+
+use geo::{Coord, Geometry, HaversineLength, LineString, Point};
+use osmpbf::{Element, ElementReader};
+use postgres::types::Type;
+use std::collections::{HashMap, HashSet};
+use wkb::geom_to_wkb;
+
+use super::{build_nodes, create_node_store, NodeStore, OsmFilter};
+
+// What an OsmPbf import produces
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum OsmProfile {
+    // Point, line and polygon tables of tagged features
+    #[default]
+    Features,
+    // Edges and vertices tables of the road network, ready for pgRouting
+    Routing,
+}
+
+// Tables a routing import is split into, suffix of their name and type of their geometry
+pub const ROUTING_LAYERS: [(&str, &str); 2] = [("edges", "LineString"), ("vertices", "Point")];
+
+// Highways that aren't roads, or not yet or anymore
+const NOT_ROUTABLE: [&str; 6] = [
+    "proposed",
+    "construction",
+    "abandoned",
+    "platform",
+    "raceway",
+    "bus_stop",
+];
+
+// Speed in km/h of highways without a usable maxspeed tag
+fn default_speed(highway: &str) -> f64 {
+    match highway {
+        "motorway" => 110.0,
+        "trunk" => 90.0,
+        "primary" => 70.0,
+        "secondary" => 60.0,
+        "tertiary" => 50.0,
+        "motorway_link" | "trunk_link" | "primary_link" | "secondary_link" | "tertiary_link" => {
+            40.0
+        }
+        "unclassified" | "residential" => 30.0,
+        "service" | "track" => 20.0,
+        "living_street" => 10.0,
+        "footway" | "path" | "pedestrian" | "steps" | "cycleway" | "bridleway" => 5.0,
+        _ => 30.0,
+    }
+}
+
+// Speed in km/h of a maxspeed tag, e.g. `50`, `30 mph` or `50;30`, the first value wins
+fn parse_maxspeed(maxspeed: &str) -> Option<f64> {
+    let value = maxspeed.split(';').next()?.trim();
+    let (number, factor) = match value.strip_suffix("mph") {
+        Some(number) => (number, 1.609344),
+        None => (value.strip_suffix("km/h").unwrap_or(value), 1.0),
+    };
+    let speed = number.trim().parse::<f64>().ok()? * factor;
+    (speed > 0.0).then_some(speed)
+}
+
+// 1 when the way can only be followed in its direction, -1 only against it, 0 both ways.
+// Motorways and roundabouts are one way unless tagged otherwise
+fn oneway(tags: &HashMap<&str, &str>) -> i32 {
+    match tags.get("oneway").copied() {
+        Some("yes" | "true" | "1") => 1,
+        Some("-1" | "reverse") => -1,
+        Some(_) => 0,
+        None if tags.get("highway") == Some(&"motorway")
+            || tags.get("junction") == Some(&"roundabout") =>
+        {
+            1
+        }
+        None => 0,
+    }
+}
+
+fn is_routable(tags: &HashMap<&str, &str>, filter: &OsmFilter) -> bool {
+    match tags.get("highway") {
+        Some(highway) => {
+            !NOT_ROUTABLE.contains(highway)
+                && tags.get("area") != Some(&"yes")
+                && filter.matches(tags.iter().map(|(key, value)| (*key, *value)))
+        }
+        None => false,
+    }
+}
+
+pub fn edge_data_types() -> Vec<NewTableTypes> {
+    [
+        ("id", Type::INT8),
+        ("source", Type::INT8),
+        ("target", Type::INT8),
+        ("cost", Type::FLOAT8),
+        ("reverse_cost", Type::FLOAT8),
+        ("oneway", Type::INT4),
+        ("highway", Type::TEXT),
+    ]
+    .into_iter()
+    .map(|(column_name, data_type)| NewTableTypes {
+        column_name: column_name.to_string(),
+        data_type,
+    })
+    .collect()
+}
+
+pub fn vertex_data_types() -> Vec<NewTableTypes> {
+    vec![NewTableTypes {
+        column_name: "id".to_string(),
+        data_type: Type::INT8,
+    }]
+}
+
+// Vertices are the nodes where routable ways end or meet, named after their OSM id
+pub struct RoutingGraph {
+    nodes: Box<dyn NodeStore>,
+    vertices: HashSet<i64>,
+}
+
+// Merge the node counts of the decoding threads
+fn merge_counts(mut a: HashMap<i64, u32>, mut b: HashMap<i64, u32>) -> HashMap<i64, u32> {
+    if a.len() < b.len() {
+        std::mem::swap(&mut a, &mut b);
+    }
+    for (id, count) in b {
+        *a.entry(id).or_insert(0) += count;
+    }
+    a
+}
+
+// Find the vertices of the network, then store the locations of the nodes of routable ways
+pub fn build_graph(args: &Cli) -> Result<RoutingGraph> {
+    let filter = OsmFilter::parse(&args.osm_filter)?;
+    let reader = ElementReader::from_path(&args.input)?;
+    let counts = reader.par_map_reduce(
+        |element| {
+            let mut counts = HashMap::new();
+            if let Element::Way(way) = element {
+                let tags: HashMap<&str, &str> = way.tags().collect();
+                if is_routable(&tags, &filter) {
+                    // Ends of a way count twice, so they always become vertices like
+                    // nodes shared by ways
+                    let refs: Vec<i64> = way.refs().collect();
+                    for (index, id) in refs.iter().enumerate() {
+                        let count = if index == 0 || index == refs.len() - 1 {
+                            2
+                        } else {
+                            1
+                        };
+                        *counts.entry(*id).or_insert(0) += count;
+                    }
+                }
+            }
+            counts
+        },
+        HashMap::new,
+        merge_counts,
+    )?;
+
+    let needed: HashSet<i64> = counts.keys().copied().collect();
+    let vertices: HashSet<i64> = counts
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(id, _)| id)
+        .collect();
+    let mut nodes = create_node_store(args.node_cache, args.node_cache_file.as_deref())?;
+    build_nodes(
+        &args.input,
+        &OsmFilter::default(),
+        Some(&needed),
+        false,
+        nodes.as_mut(),
+        |_| {},
+    )?;
+    println!(
+        "{} node locations cached, {} vertices",
+        nodes.len(),
+        vertices.len()
+    );
+
+    Ok(RoutingGraph { nodes, vertices })
+}
+
+impl RoutingGraph {
+    pub fn vertex_rows(&self) -> impl Iterator<Item = Result<Row>> + '_ {
+        self.vertices.iter().filter_map(|id| {
+            let coord = self.nodes.get(*id)?;
+            let mut row = Row::new();
+            row.add(AcceptedTypes::BigInt(Some(*id)));
+            Some(to_wkb(&Geometry::Point(Point(coord))).map(|wkb| {
+                row.add(AcceptedTypes::Geometry(Some(Wkb { geometry: wkb })));
+                row
+            }))
+        })
+    }
+
+    // Split a way at its vertices, into the nodes of each edge
+    fn split<'a>(&self, refs: &'a [i64]) -> Vec<&'a [i64]> {
+        let mut edges = Vec::new();
+        let mut start = 0;
+        for index in 1..refs.len() {
+            if self.vertices.contains(&refs[index]) {
+                edges.push(&refs[start..=index]);
+                start = index;
+            }
+        }
+        edges
+    }
+}
+
+fn to_wkb(geometry: &Geometry) -> Result<Vec<u8>> {
+    geom_to_wkb(geometry)
+        .map_err(|_| Error::FailedValidation("❌ Could not convert geometry to WKB".into()))
+}
+
+fn edge_row(
+    id: i64,
+    refs: &[i64],
+    tags: &HashMap<&str, &str>,
+    nodes: &dyn NodeStore,
+) -> Result<Option<Row>> {
+    let coords: Vec<Coord<f64>> = refs.iter().filter_map(|id| nodes.get(*id)).collect();
+    // Nodes missing from the file, e.g. cut off by an extract, leave the edge incomplete
+    if coords.len() != refs.len() {
+        return Ok(None);
+    }
+    let line = LineString::from(coords);
+    let highway = tags.get("highway").copied().unwrap_or_default();
+    let speed = tags
+        .get("maxspeed")
+        .and_then(|maxspeed| parse_maxspeed(maxspeed))
+        .unwrap_or_else(|| default_speed(highway));
+    // Travel time in seconds, negative in a direction the edge can't be followed
+    let cost = line.haversine_length() / (speed / 3.6);
+    let oneway = oneway(tags);
+
+    let mut row = Row::new();
+    row.add(AcceptedTypes::BigInt(Some(id)));
+    row.add(AcceptedTypes::BigInt(refs.first().copied()));
+    row.add(AcceptedTypes::BigInt(refs.last().copied()));
+    row.add(AcceptedTypes::Float(Some(if oneway == -1 {
+        -1.0
+    } else {
+        cost
+    })));
+    row.add(AcceptedTypes::Float(Some(if oneway == 1 {
+        -1.0
+    } else {
+        cost
+    })));
+    row.add(AcceptedTypes::Int(Some(oneway)));
+    row.add(AcceptedTypes::Text(Some(highway.to_string())));
+    let wkb = to_wkb(&Geometry::LineString(line))?;
+    row.add(AcceptedTypes::Geometry(Some(Wkb { geometry: wkb })));
+    Ok(Some(row))
+}
+
+// Edges are numbered from 1 in file order. Ids only hold within one import, which is why
+// routing tables can't be appended to
+pub fn read_edges(args: &Cli, graph: RoutingGraph) -> Result<RowStream> {
+    let args = args.clone();
+    Ok(stream_rows(move |sender| {
+        let filter = OsmFilter::parse(&args.osm_filter)?;
+        let reader = ElementReader::from_path(&args.input)?;
+        let mut id = 0;
+        let mut skipped = 0;
+        let mut error = None;
+        reader.for_each(|element| {
+            let Element::Way(way) = element else {
+                return;
+            };
+            let tags: HashMap<&str, &str> = way.tags().collect();
+            if !is_routable(&tags, &filter) {
+                return;
+            }
+            let refs: Vec<i64> = way.refs().collect();
+            for edge in graph.split(&refs) {
+                match edge_row(id + 1, edge, &tags, graph.nodes.as_ref()) {
+                    Ok(Some(row)) => {
+                        id += 1;
+                        // Sending only fails once the writer has gone away
+                        let _ = sender.send(Ok(row));
+                    }
+                    Ok(None) => skipped += 1,
+                    Err(err) => {
+                        error.get_or_insert(err);
+                    }
+                }
+            }
+        })?;
+        if let Some(err) = error {
+            return Err(err);
+        }
+        if skipped > 0 {
+            println!("❌ {} edges with missing nodes skipped", skipped);
+        }
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edge_attributes() {
+        assert_eq!(parse_maxspeed("50"), Some(50.0));
+        assert_eq!(parse_maxspeed("50 km/h;30"), Some(50.0));
+        assert!((parse_maxspeed("30 mph").unwrap() - 48.28).abs() < 0.01);
+        assert_eq!(parse_maxspeed("none"), None);
+
+        let tags = HashMap::from([("highway", "motorway")]);
+        assert_eq!(oneway(&tags), 1);
+        let tags = HashMap::from([("highway", "motorway"), ("oneway", "no")]);
+        assert_eq!(oneway(&tags), 0);
+        let tags = HashMap::from([("highway", "residential"), ("oneway", "-1")]);
+        assert_eq!(oneway(&tags), -1);
+        assert!(is_routable(&tags, &OsmFilter::default()));
+        let tags = HashMap::from([("highway", "construction")]);
+        assert!(!is_routable(&tags, &OsmFilter::default()));
+    }
+
+    #[test]
+    fn test_read_edges() {
+        let args = Cli {
+            input: "examples/osmpbf/monaco-latest.osm.pbf".to_string(),
+            uri: "postgresql://localhost:5432/postgis".to_string(),
+            table: "monaco".to_string(),
+            ..Default::default()
+        };
+        let graph = build_graph(&args).unwrap();
+        let vertices = graph.vertices.clone();
+        assert_eq!(graph.vertex_rows().count(), vertices.len());
+
+        let edges = read_edges(&args, graph).unwrap();
+        let edges = edges.iter().collect::<Result<Vec<Row>>>().unwrap();
+        assert!(!edges.is_empty());
+        // Edges go from a vertex to another
+        for edge in edges.iter() {
+            let (AcceptedTypes::BigInt(Some(source)), AcceptedTypes::BigInt(Some(target))) =
+                (&edge.columns[1], &edge.columns[2])
+            else {
+                panic!("❌ Source and target not found");
+            };
+            assert!(vertices.contains(source) && vertices.contains(target));
+        }
+    }
+}
@@ -2,175 +2,298 @@ use crate::{Error, Result};
 
 use postgres::types::Type;
 use proj::{Proj, Transform};
-use shapefile::dbase::FieldValue;
-use std::collections::HashMap;
+use shapefile::dbase::{FieldName, FieldValue, Record, TableWriterBuilder};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 
-use crate::format::common::{AcceptedTypes, NewTableTypes, Row, Rows};
-use crate::format::geo::to_geo;
+use crate::format::common::{
+    convert_in_parallel, report_extra_columns, stream_rows, AcceptedTypes, NewTableTypes,
+    OnMixedTypes, Row, RowStream, SchemaInference,
+};
+use crate::format::geo::{to_ewkb, to_geo, Dims};
 use crate::pg::binary_copy::Wkb;
 use crate::utils::cli::Cli;
-use wkb::geom_to_wkb;
+use crate::utils::crs::{transformer, wkt_to_srid};
+use wkb::{geom_to_wkb, wkb_to_geom};
 
-pub fn determine_data_types(file_path: &str) -> Result<Vec<NewTableTypes>> {
-    let mut table_config: HashMap<String, Type> = HashMap::new();
+// Infer column types from the first `sample` records, or all of them
+pub fn determine_data_types(
+    file_path: &str,
+    sample: Option<usize>,
+    on_mixed_types: OnMixedTypes,
+) -> Result<Vec<NewTableTypes>> {
+    let mut schema = SchemaInference::new(on_mixed_types);
     let mut reader = shapefile::Reader::from_path(file_path)?;
-    for shape_record in reader.iter_shapes_and_records() {
-        let (_, record) = shape_record.unwrap();
-        for (column_name, data_type) in record.into_iter() {
-            match data_type {
-                FieldValue::Numeric(_) => {
-                    if table_config.contains_key(&column_name)
-                        && table_config[&column_name] == Type::FLOAT8
-                    {
-                        continue;
-                    } else if table_config.contains_key(&column_name)
-                        && table_config[&column_name] != Type::INT8
-                    {
-                        return Err(Error::MixedDataTypes(
-                            "❌ Column contains mixed data types".to_string(),
-                        ));
-                    } else {
-                        table_config.insert(column_name, Type::FLOAT8);
-                    }
-                }
-                FieldValue::Float(_) => {
-                    if table_config.contains_key(&column_name)
-                        && table_config[&column_name] == Type::FLOAT8
-                    {
-                        continue;
-                    } else if table_config.contains_key(&column_name)
-                        && table_config[&column_name] != Type::INT8
-                    {
-                        return Err(Error::MixedDataTypes(
-                            "❌ Column contains mixed data types".to_string(),
-                        ));
-                    } else {
-                        table_config.insert(column_name, Type::FLOAT8);
-                    }
-                }
-                FieldValue::Double(_) => {
-                    if table_config.contains_key(&column_name)
-                        && table_config[&column_name] == Type::FLOAT8
-                    {
-                        continue;
-                    } else if table_config.contains_key(&column_name)
-                        && table_config[&column_name] != Type::INT8
-                    {
-                        return Err(Error::MixedDataTypes(
-                            "❌ Column contains mixed data types".to_string(),
-                        ));
-                    } else {
-                        table_config.insert(column_name, Type::FLOAT8);
-                    }
-                }
-                FieldValue::Integer(_) => {
-                    if table_config.contains_key(&column_name)
-                        && table_config[&column_name] == Type::INT8
-                    {
-                        continue;
-                    } else if table_config.contains_key(&column_name)
-                        && table_config[&column_name] != Type::FLOAT8
-                    {
-                        return Err(Error::MixedDataTypes(
-                            "❌ Column contains mixed data types".to_string(),
-                        ));
-                    } else {
-                        table_config.insert(column_name, Type::INT8);
-                    }
-                }
-                FieldValue::Character(_) => {
-                    if table_config.contains_key(&column_name)
-                        && table_config[&column_name] == Type::TEXT
-                    {
-                        continue;
-                    } else if table_config.contains_key(&column_name)
-                        && table_config[&column_name] != Type::INT8
-                    {
-                        return Err(Error::MixedDataTypes(
-                            "❌ Column contains mixed data types".to_string(),
-                        ));
-                    } else {
-                        table_config.insert(column_name, Type::TEXT);
-                    }
+    let mut unsupported: HashSet<String> = HashSet::new();
+    for shape_record in reader
+        .iter_shapes_and_records()
+        .take(sample.unwrap_or(usize::MAX))
+    {
+        let (_, record) = shape_record?;
+        for (column_name, value) in record.into_iter() {
+            let data_type = match value {
+                FieldValue::Numeric(_) | FieldValue::Float(_) | FieldValue::Double(_) => {
+                    Type::FLOAT8
                 }
-                FieldValue::Logical(_) => {
-                    if table_config.contains_key(&column_name)
-                        && table_config[&column_name] == Type::BOOL
-                    {
-                        continue;
-                    } else if table_config.contains_key(&column_name)
-                        && table_config[&column_name] != Type::INT8
-                    {
-                        return Err(Error::MixedDataTypes(
-                            "❌ Column contains mixed data types".to_string(),
-                        ));
-                    } else {
-                        table_config.insert(column_name, Type::BOOL);
+                FieldValue::Integer(_) => Type::INT8,
+                FieldValue::Character(_) => Type::TEXT,
+                FieldValue::Logical(_) => Type::BOOL,
+                _ => {
+                    if unsupported.insert(column_name.clone()) {
+                        println!("❌ Type currently not supported: {}", column_name);
                     }
+                    continue;
                 }
-                _ => println!("❌ Type currently not supported"),
-            }
+            };
+            schema.add(&column_name, Some(data_type))?;
         }
     }
+    let mut table_config: HashMap<String, Type> = schema
+        .finish()
+        .into_iter()
+        .map(|column| (column.column_name, column.data_type))
+        .collect();
 
+    // Columns follow the order of the fields in the .dbf
+    let dbf_path = Path::new(file_path).with_extension("dbf");
+    let dbf_reader =
+        shapefile::dbase::Reader::from_path(dbf_path).map_err(shapefile::Error::from)?;
     let mut data_types: Vec<NewTableTypes> = Vec::new();
-    for (column_name, data_type) in table_config.iter() {
-        data_types.push(NewTableTypes {
-            column_name: column_name.clone(),
-            data_type: data_type.clone(),
-        });
+    for field in dbf_reader.fields() {
+        if let Some(data_type) = table_config.remove(field.name()) {
+            data_types.push(NewTableTypes {
+                column_name: field.name().to_string(),
+                data_type,
+            });
+        }
     }
 
     Ok(data_types)
 }
 
-pub fn read_shapefile(args: &Cli) -> Result<Rows> {
-    let mut rows = Rows::new();
-    let mut reader = shapefile::Reader::from_path(&args.input)?;
-    for shape_record in reader.iter_shapes_and_records() {
-        let mut row = Row::new();
-        let (shape, record) = shape_record?;
-        for (_, data_type) in record.into_iter() {
-            match data_type {
-                FieldValue::Numeric(value) => {
-                    row.add(AcceptedTypes::Float(value));
-                }
-                FieldValue::Float(value) => {
-                    row.add(AcceptedTypes::Double(value));
-                }
-                FieldValue::Double(value) => {
-                    row.add(AcceptedTypes::Float(Some(value)));
-                }
-                FieldValue::Integer(value) => {
-                    row.add(AcceptedTypes::Int(Some(value)));
-                }
-                FieldValue::Character(value) => {
-                    row.add(AcceptedTypes::Text(value));
-                }
-                FieldValue::Logical(value) => {
-                    row.add(AcceptedTypes::Bool(value));
-                }
-                _ => println!("❌ Type currently not supported"),
+// SRID of the .prj next to the shapefile, if it has one
+pub fn determine_srid(file_path: &str) -> Result<Option<i32>> {
+    let prj_path = Path::new(file_path).with_extension("prj");
+    if !prj_path.exists() {
+        return Ok(None);
+    }
+    let wkt = std::fs::read_to_string(&prj_path)?;
+    match wkt_to_srid(wkt.trim()) {
+        Some(srid) => Ok(Some(srid)),
+        None => Err(Error::FailedValidation(format!(
+            "❌ Could not identify the CRS in '{}', set its SRID with --srid",
+            prj_path.display()
+        ))),
+    }
+}
+
+// Geometry column type, from the dimensions of the shapes in the file
+pub fn geometry_type(file_path: &str) -> Result<&'static str> {
+    let reader = shapefile::ShapeReader::from_path(file_path)?;
+    Ok(Dims::from_header(reader.header()).geometry_type())
+}
+
+fn to_value(value: FieldValue, data_type: &Type) -> AcceptedTypes {
+    let value = match value {
+        FieldValue::Numeric(value) => AcceptedTypes::Float(value),
+        FieldValue::Float(value) => AcceptedTypes::Double(value),
+        FieldValue::Double(value) => AcceptedTypes::Float(Some(value)),
+        FieldValue::Integer(value) => AcceptedTypes::Int(Some(value)),
+        FieldValue::Character(value) => AcceptedTypes::Text(value),
+        FieldValue::Logical(value) => AcceptedTypes::Bool(value),
+        _ => AcceptedTypes::null(data_type),
+    };
+    value.coerce(data_type)
+}
+
+// Shapes with Z or M values are written as they are, geo only has two dimensions
+fn shape_to_wkb(
+    shape: &shapefile::Shape,
+    dims: Dims,
+    promote: bool,
+    proj: Option<&Proj>,
+) -> Result<Option<Vec<u8>>> {
+    if dims != Dims::default() {
+        return to_ewkb(shape, dims, promote, proj);
+    }
+    match to_geo(shape, promote)? {
+        Some(mut geom) => {
+            // Reproject
+            if let Some(proj) = proj {
+                geom.transform(proj)?;
             }
+            let wkb = geom_to_wkb(&geom).map_err(|_| {
+                Error::FailedValidation("❌ Could not convert geometry to WKB".into())
+            })?;
+            Ok(Some(wkb))
         }
+        None => Ok(None),
+    }
+}
+
+// Rows hold one value per column of config, in config order, with the geometry last
+pub fn read_shapefile(args: &Cli, config: &[NewTableTypes]) -> Result<RowStream> {
+    let args = args.clone();
+    let config = config.to_vec();
+    Ok(stream_rows(move |sender| {
+        let mut reader = shapefile::Reader::from_path(&args.input)?;
+        let dims = Dims::from_header(reader.header());
+        // Definitions PROJ can't read fail here, before any thread needs them
+        transformer(&args)?;
+        let mut reported: HashSet<String> = HashSet::new();
+        // Records are read in order, shapes converted and reprojected in parallel
+        let features = reader.iter_shapes_and_records().map(|shape_record| {
+            let mut row = Row::new();
+            let (shape, mut record) = shape_record?;
+            for column in config.iter() {
+                let value = match record.remove(&column.column_name) {
+                    Some(value) => to_value(value, &column.data_type),
+                    None => AcceptedTypes::null(&column.data_type),
+                };
+                row.add(value);
+            }
+            let extra: Vec<String> = record.into_iter().map(|(name, _)| name).collect();
+            report_extra_columns(&extra, &mut reported);
+            Ok((row, shape))
+        });
+        convert_in_parallel(
+            features,
+            args.threads,
+            sender,
+            || transformer(&args),
+            |proj, (mut row, shape)| {
+                let wkb = shape_to_wkb(&shape, dims, args.promote_to_multi, proj.as_ref())?;
+                // Null shapes have a null geometry
+                row.add(AcceptedTypes::Geometry(
+                    wkb.map(|geometry| Wkb { geometry }),
+                ));
+                Ok(row)
+            },
+        )
+    }))
+}
 
-        let mut geom = to_geo(&shape)?;
-        // Reproject
-        geom = if args.reproject.is_some() {
-            let from = format!("EPSG:{}", args.srid.unwrap());
-            let to = format!("EPSG:{}", args.reproject.unwrap());
-            let proj = Proj::new_known_crs(&from, &to, None)?;
-            geom.transform(&proj)?;
-            geom
-        } else {
-            geom
+// dBase field names are limited to 10 characters, truncate and deduplicate them
+fn to_field_name(column_name: &str, used: &mut HashSet<String>) -> String {
+    let truncated: String = column_name.chars().take(10).collect();
+    let mut name = truncated.clone();
+    let mut suffix = 1;
+    while used.contains(&name) {
+        let suffix_str = suffix.to_string();
+        let keep = 10 - suffix_str.len();
+        name = format!(
+            "{}{}",
+            truncated.chars().take(keep).collect::<String>(),
+            suffix_str
+        );
+        suffix += 1;
+    }
+    used.insert(name.clone());
+    name
+}
+
+// Write rows, with values in config order and the geometry last, as a .shp/.shx/.dbf bundle
+// and, if the spatial reference is known, its .prj
+pub fn write_shapefile<I>(
+    file_path: &str,
+    config: &[NewTableTypes],
+    rows: I,
+    srtext: Option<String>,
+) -> Result<u64>
+where
+    I: IntoIterator<Item = Result<Row>>,
+{
+    let mut used: HashSet<String> = HashSet::new();
+    let mut field_names: Vec<String> = Vec::new();
+    let mut table_builder = TableWriterBuilder::new();
+    for column in config.iter() {
+        let name = to_field_name(&column.column_name, &mut used);
+        let field_name = FieldName::try_from(name.as_str())
+            .map_err(|err| Error::FailedValidation(format!("❌ {}: {}", err, name)))?;
+        table_builder = match column.data_type {
+            Type::INT4 | Type::INT8 => table_builder.add_numeric_field(field_name, 18, 0),
+            Type::FLOAT8 => table_builder.add_numeric_field(field_name, 24, 15),
+            Type::BOOL => table_builder.add_logical_field(field_name),
+            _ => table_builder.add_character_field(field_name, 254),
         };
-        let wkb = geom_to_wkb(&geom).expect("❌ Failed to insert node into database");
-        row.add(AcceptedTypes::Geometry(Some(Wkb { geometry: wkb })));
-        rows.add(row);
+        field_names.push(name);
     }
 
-    Ok(rows)
+    let mut writer = shapefile::Writer::from_path(file_path, table_builder)?;
+    let mut count = 0;
+    let mut skipped = 0;
+    for row in rows {
+        let mut values = row?.columns;
+        let geom = match values.pop() {
+            Some(AcceptedTypes::Geometry(Some(wkb))) => {
+                wkb_to_geom(&mut wkb.geometry.as_slice())
+                    .map_err(|_| Error::FailedValidation("❌ Could not read WKB geometry".into()))?
+            }
+            // Null geometries cannot be written to a shapefile
+            _ => {
+                skipped += 1;
+                continue;
+            }
+        };
+
+        let mut record = Record::default();
+        for (name, value) in field_names.iter().zip(values) {
+            let value = match value {
+                AcceptedTypes::Int(value) => FieldValue::Numeric(value.map(f64::from)),
+                AcceptedTypes::BigInt(value) => FieldValue::Numeric(value.map(|v| v as f64)),
+                AcceptedTypes::Float(value) => FieldValue::Numeric(value),
+                AcceptedTypes::Double(value) => FieldValue::Numeric(value.map(f64::from)),
+                AcceptedTypes::Bool(value) => FieldValue::Logical(value),
+                AcceptedTypes::Text(value) => FieldValue::Character(value),
+                AcceptedTypes::Array(value) => FieldValue::Character(value.map(|v| v.join(","))),
+                AcceptedTypes::Json(value) => FieldValue::Character(value.map(|v| v.to_string())),
+                AcceptedTypes::Hstore(value) => FieldValue::Character(value.map(|tags| {
+                    tags.into_iter()
+                        .map(|(key, value)| format!("{}={}", key, value.unwrap_or_default()))
+                        .collect::<Vec<String>>()
+                        .join(",")
+                })),
+                AcceptedTypes::Geometry(_) => FieldValue::Character(None),
+            };
+            record.insert(name.clone(), value);
+        }
+
+        match geom {
+            geo::Geometry::Point(point) => {
+                writer.write_shape_and_record(&shapefile::Point::from(point), &record)?
+            }
+            geo::Geometry::MultiPoint(points) => {
+                writer.write_shape_and_record(&shapefile::Multipoint::from(points), &record)?
+            }
+            geo::Geometry::LineString(line) => {
+                writer.write_shape_and_record(&shapefile::Polyline::from(line), &record)?
+            }
+            geo::Geometry::MultiLineString(lines) => {
+                writer.write_shape_and_record(&shapefile::Polyline::from(lines), &record)?
+            }
+            geo::Geometry::Polygon(poly) => {
+                writer.write_shape_and_record(&shapefile::Polygon::from(poly), &record)?
+            }
+            geo::Geometry::MultiPolygon(polys) => {
+                writer.write_shape_and_record(&shapefile::Polygon::from(polys), &record)?
+            }
+            _ => {
+                return Err(Error::UnsupportedShapeType(
+                    "❌ Geometry type cannot be written to a shapefile".into(),
+                ))
+            }
+        }
+        count += 1;
+    }
+
+    if skipped > 0 {
+        println!("❌ {} rows without geometry skipped", skipped);
+    }
+    if let Some(srtext) = srtext {
+        std::fs::write(Path::new(file_path).with_extension("prj"), srtext)?;
+    }
+
+    Ok(count)
 }
 
 #[cfg(test)]
@@ -180,7 +303,7 @@ mod tests {
     #[test]
     fn test_determine_data_types() {
         let file_path = "examples/shapefile/andalucia.shp";
-        let data_types = determine_data_types(file_path).unwrap();
+        let data_types = determine_data_types(file_path, None, OnMixedTypes::Text).unwrap();
         assert_eq!(data_types.len(), 2);
         for data_type in data_types {
             if data_type.column_name == "x" || data_type.column_name == "y" {
@@ -189,6 +312,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_determine_srid() {
+        let file_path = std::env::temp_dir().join("popgis_test_srid.shp");
+        let file_path = file_path.to_str().unwrap();
+        assert_eq!(determine_srid(file_path).unwrap(), None);
+        let prj_path = Path::new(file_path).with_extension("prj");
+        std::fs::write(
+            &prj_path,
+            r#"PROJCS["ETRS89 / UTM zone 30N",GEOGCS["ETRS89",AUTHORITY["EPSG","4258"]],AUTHORITY["EPSG","25830"]]"#,
+        )
+        .unwrap();
+        assert_eq!(determine_srid(file_path).unwrap(), Some(25830));
+        std::fs::remove_file(prj_path).unwrap();
+    }
+
     #[test]
     fn test_read_shapefile() {
         let file_path = "examples/shapefile/andalucia.shp";
@@ -202,8 +340,131 @@ mod tests {
             table: "andalucia".to_string(),
             schema: None,
             mode: None,
+            ..Default::default()
         };
-        let rows = read_shapefile(&args).unwrap();
-        assert_eq!(rows.row.len(), 36);
+        let config = determine_data_types(file_path, None, OnMixedTypes::Text).unwrap();
+        let rows = read_shapefile(&args, &config).unwrap();
+        let rows = rows.iter().collect::<Result<Vec<Row>>>().unwrap();
+        assert_eq!(rows.len(), 36);
+        assert!(rows.iter().all(|row| row.columns.len() == config.len() + 1));
+    }
+
+    #[test]
+    fn test_read_shapefile_z() {
+        let file_path = std::env::temp_dir().join("popgis_test_z.shp");
+        let file_path = file_path.to_str().unwrap();
+        let name = FieldName::try_from("name").unwrap();
+        let table_builder = TableWriterBuilder::new().add_character_field(name, 20);
+        let mut writer = shapefile::Writer::from_path(file_path, table_builder).unwrap();
+        let mut record = Record::default();
+        record.insert(
+            "name".to_string(),
+            FieldValue::Character(Some("Mulhacén".into())),
+        );
+        let point = shapefile::PointZ::new(-3.31, 37.05, 3479.0, shapefile::NO_DATA);
+        writer.write_shape_and_record(&point, &record).unwrap();
+        drop(writer);
+
+        assert_eq!(geometry_type(file_path).unwrap(), "GeometryZ");
+        let args = Cli {
+            input: file_path.to_string(),
+            ..Default::default()
+        };
+        let config = determine_data_types(file_path, None, OnMixedTypes::Text).unwrap();
+        let rows = read_shapefile(&args, &config).unwrap();
+        let rows = rows.iter().collect::<Result<Vec<Row>>>().unwrap();
+        let AcceptedTypes::Geometry(Some(wkb)) = &rows[0].columns[1] else {
+            panic!("❌ Geometry not found");
+        };
+        // Point with a Z flag, followed by X, Y and Z
+        assert_eq!(wkb.geometry.len(), 1 + 4 + 3 * 8);
+        assert_eq!(wkb.geometry[1..5], 0x8000_0001u32.to_le_bytes());
+        for extension in ["shp", "shx", "dbf"] {
+            std::fs::remove_file(Path::new(file_path).with_extension(extension)).unwrap();
+        }
+    }
+
+    // Reprojecting with a transformer per feature, as popgis used to, against one per thread.
+    // Run with `just bench-reproject`
+    #[test]
+    #[ignore]
+    fn bench_reproject() {
+        let file_path = "examples/shapefile/andalucia.shp";
+        let passes = 100;
+        let args = Cli {
+            input: file_path.to_string(),
+            srid: Some(4326),
+            reproject: Some(3857),
+            ..Default::default()
+        };
+        let config = determine_data_types(file_path, None, OnMixedTypes::Text).unwrap();
+
+        let start = std::time::Instant::now();
+        for _ in 0..passes {
+            for shape in shapefile::read_shapes(file_path).unwrap() {
+                let proj = Proj::new_known_crs("EPSG:4326", "EPSG:3857", None).unwrap();
+                let mut geom = to_geo(&shape, false).unwrap().unwrap();
+                geom.transform(&proj).unwrap();
+                geom_to_wkb(&geom).unwrap();
+            }
+        }
+        let per_feature = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for _ in 0..passes {
+            let rows = read_shapefile(&args, &config).unwrap();
+            let rows = rows.iter().collect::<Result<Vec<Row>>>().unwrap();
+            assert_eq!(rows.len(), 36);
+        }
+        let per_thread = start.elapsed();
+
+        println!(
+            "{} features reprojected, {:?} with a transformer per feature, {:?} with one per thread ({:.1}x)",
+            36 * passes,
+            per_feature,
+            per_thread,
+            per_feature.as_secs_f64() / per_thread.as_secs_f64()
+        );
+    }
+
+    #[test]
+    fn test_to_field_name() {
+        let mut used = HashSet::new();
+        assert_eq!(to_field_name("name", &mut used), "name");
+        assert_eq!(to_field_name("population_2020", &mut used), "population");
+        assert_eq!(to_field_name("population_2021", &mut used), "populatio1");
+    }
+
+    #[test]
+    fn test_write_shapefile() {
+        let file_path = std::env::temp_dir().join("popgis_test_write.shp");
+        let file_path = file_path.to_str().unwrap();
+        let config = vec![NewTableTypes {
+            column_name: "name".to_string(),
+            data_type: Type::TEXT,
+        }];
+        let mut row = Row::new();
+        row.add(AcceptedTypes::Text(Some("Sevilla".to_string())));
+        let geom = geo::Geometry::Point(geo::Point::new(-5.98, 37.38));
+        row.add(AcceptedTypes::Geometry(Some(Wkb {
+            geometry: geom_to_wkb(&geom).unwrap(),
+        })));
+
+        let srtext = Some("GEOGCS[\"WGS 84\"]".to_string());
+        let count = write_shapefile(file_path, &config, vec![Ok(row)], srtext).unwrap();
+        assert_eq!(count, 1);
+
+        let records = shapefile::read(file_path).unwrap();
+        assert_eq!(records.len(), 1);
+        let (shape, record) = &records[0];
+        assert_eq!(to_geo(shape, false).unwrap(), Some(geom));
+        assert_eq!(
+            record.get("name"),
+            Some(&FieldValue::Character(Some("Sevilla".to_string())))
+        );
+        assert!(Path::new(file_path).with_extension("prj").exists());
+        for extension in ["shp", "shx", "dbf", "prj"] {
+            std::fs::remove_file(Path::new(file_path).with_extension(extension)).unwrap();
+        }
     }
 }
@@ -2,14 +2,14 @@ use crate::Result;
 use bytes::BytesMut;
 use postgres::types::to_sql_checked;
 use postgres::types::{IsNull, ToSql, Type};
-use postgres::Statement;
 use std::error::Error;
 
-use postgres::binary_copy::BinaryCopyInWriter;
-use postgres::CopyInWriter;
+use postgres::binary_copy::{BinaryCopyInWriter, BinaryCopyOutIter};
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::{Client, CopyInWriter, GenericClient};
 
-use crate::pg::crud::create_connection;
-use crate::format::common::{AcceptedTypes, NewTableTypes, Rows};
+use crate::format::common::{AcceptedTypes, NewTableTypes, Row};
+use crate::pg::crud::{qualified_name, quote_ident};
 
 #[derive(Debug)]
 pub struct Wkb {
@@ -33,41 +33,36 @@ impl ToSql for Wkb {
     to_sql_checked!();
 }
 
-pub fn infer_geom_type(stmt: Statement) -> Result<Type> {
-    let column = stmt.columns().first().expect("❌ Failed to get columns");
-    Ok(column.type_().clone())
-}
-
-pub fn insert_rows(
-    rows: &Rows,
+pub fn insert_rows<I, C>(
+    rows: I,
     config: &[NewTableTypes],
-    geom_type: Type,
-    uri: &str,
+    client: &mut C,
     schema: &Option<String>,
     table: &str,
-) -> Result<()> {
-    // Create connection
-    let mut client = create_connection(uri)?;
-
-    // Prepare types for binary copy
-    let mut types: Vec<Type> = Vec::new();
-    for column in config.iter() {
-        types.push(column.data_type.clone());
-    }
-    types.push(geom_type);
+) -> Result<()>
+where
+    I: IntoIterator<Item = Result<Row>>,
+    C: GenericClient,
+{
+    let table = qualified_name(table, schema);
+    let mut columns: Vec<String> = config
+        .iter()
+        .map(|column| quote_ident(&column.column_name))
+        .collect();
+    columns.push("geom".into());
+    let columns = columns.join(", ");
+
+    // Prepare types for binary copy, as found in the table. Types added by extensions,
+    // like geometry or hstore, don't have a fixed oid
+    let stmt = client.prepare(&format!("SELECT {} FROM {} LIMIT 0", columns, table))?;
+    let types: Vec<Type> = stmt
+        .columns()
+        .iter()
+        .map(|column| column.type_().clone())
+        .collect();
 
     // Binary copy in writer
-    let mut query = String::from("COPY ");
-    if let Some(schema) = schema {
-        query.push_str(&format!("{}.{}", schema, table));
-    } else {
-        query.push_str(table);
-    }
-    query.push_str(" (");
-    for column in config.iter() {
-        query.push_str(&format!("{},", column.column_name));
-    }
-    query.push_str("geom) FROM stdin BINARY");
+    let query = format!("COPY {} ({}) FROM stdin BINARY", table, columns);
     let writer: CopyInWriter = client.copy_in(&query)?;
 
     let mut writer = BinaryCopyInWriter::new(writer, &types);
@@ -79,14 +74,26 @@ pub fn insert_rows(
 
     println!("Inserting data into database...");
 
-    for row in rows.row.iter() {
+    let mut count = 0;
+    for row in rows {
+        // Convert values to the type of the column they are written to
+        let values: Vec<AcceptedTypes> = row?
+            .columns
+            .into_iter()
+            .zip(types.iter())
+            .map(|(value, data_type)| value.coerce(data_type))
+            .collect();
+
         // Transform row into vector of ToSql
         let mut tosql: Vec<&(dyn ToSql + Sync)> = Vec::new();
-        for column in row.columns.iter() {
+        for column in values.iter() {
             match column {
                 AcceptedTypes::Int(value) => {
                     tosql.push(value);
                 }
+                AcceptedTypes::BigInt(value) => {
+                    tosql.push(value);
+                }
                 AcceptedTypes::Float(value) => {
                     tosql.push(value);
                 }
@@ -102,6 +109,12 @@ pub fn insert_rows(
                 AcceptedTypes::Array(value) => {
                     tosql.push(value);
                 }
+                AcceptedTypes::Json(value) => {
+                    tosql.push(value);
+                }
+                AcceptedTypes::Hstore(value) => {
+                    tosql.push(value);
+                }
                 AcceptedTypes::Geometry(value) => {
                     tosql.push(value);
                 }
@@ -112,15 +125,70 @@ pub fn insert_rows(
         let vec_slice: &[&(dyn ToSql + Sync)] = &tosql;
 
         // Write row to database
-        writer
-            .write(vec_slice)
-            .expect("❌ Failed to insert row into database");
+        writer.write(vec_slice)?;
+        count += 1;
     }
 
     // Finish writing
     writer.finish()?;
 
-    println!("🎉 Data sucessfully inserted into database");
+    println!("🎉 {} rows sucessfully inserted into database", count);
 
     Ok(())
 }
+
+// Stream the result of a query out of the database, with values in config order and the geometry last
+pub fn copy_out_rows<'a>(
+    client: &'a mut Client,
+    query: &str,
+    config: &'a [NewTableTypes],
+    geom_column: &str,
+) -> Result<impl Iterator<Item = Result<Row>> + 'a> {
+    // Prepare types for binary copy, casting each column to the type it's read as
+    let mut types: Vec<Type> = Vec::new();
+    let mut select = String::from("COPY (SELECT ");
+    for column in config.iter() {
+        types.push(column.data_type.clone());
+        let cast = match column.data_type {
+            Type::INT4 => "INT",
+            Type::INT8 => "BIGINT",
+            Type::FLOAT8 => "DOUBLE PRECISION",
+            Type::BOOL => "BOOL",
+            Type::TEXT_ARRAY => "TEXT[]",
+            _ => "TEXT",
+        };
+        select.push_str(&format!("{}::{},", quote_ident(&column.column_name), cast));
+    }
+    types.push(Type::BYTEA);
+    select.push_str(&format!(
+        "ST_AsBinary({}) FROM ({}) AS popgis) TO STDOUT BINARY",
+        quote_ident(geom_column),
+        query
+    ));
+
+    let reader = client.copy_out(&select)?;
+    let rows = BinaryCopyOutIter::new(reader, &types)
+        .iterator()
+        .map(move |copy_row| {
+            let copy_row = copy_row?;
+            let mut row = Row::new();
+            for (index, column) in config.iter().enumerate() {
+                let value = match column.data_type {
+                    Type::INT4 => AcceptedTypes::Int(copy_row.try_get(index)?),
+                    Type::INT8 => AcceptedTypes::BigInt(copy_row.try_get(index)?),
+                    Type::FLOAT8 => AcceptedTypes::Float(copy_row.try_get(index)?),
+                    Type::BOOL => AcceptedTypes::Bool(copy_row.try_get(index)?),
+                    Type::TEXT_ARRAY => AcceptedTypes::Array(copy_row.try_get(index)?),
+                    _ => AcceptedTypes::Text(copy_row.try_get(index)?),
+                };
+                row.add(value);
+            }
+            let geometry: Option<Vec<u8>> = copy_row.try_get(config.len())?;
+            row.add(AcceptedTypes::Geometry(
+                geometry.map(|geometry| Wkb { geometry }),
+            ));
+            Ok(row)
+        });
+
+    Ok(rows)
+}
@@ -1,117 +1,603 @@
 use crate::{Error, Result};
-use postgres::types::Type;
-use postgres::Statement;
+use postgres::types::{Kind, Type};
 
-use postgres::{Client, NoTls};
+use postgres::{Client, GenericClient, NoTls};
 
-use crate::format::common::NewTableTypes;
+use crate::format::common::{can_coerce, NewTableTypes};
 
 pub fn create_connection(uri: &str) -> Result<Client> {
     let client = Client::connect(uri, NoTls)?;
     Ok(client)
 }
 
-pub fn create_schema(schema_name: &str, uri: &str) -> Result<()> {
-    let mut client = create_connection(uri)?;
-    client.batch_execute(&format!("CREATE SCHEMA IF NOT EXISTS {}", schema_name))?;
-    Ok(())
+// Quote an identifier so names with spaces, capitals or reserved words are kept as they are
+pub fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
 }
 
-pub fn get_stmt(table_name: &str, schema_name: &Option<String>, uri: &str) -> Result<Statement> {
-    let mut client = create_connection(uri)?;
-    let stmt = if let Some(schema) = schema_name {
-        client.prepare(&format!("SELECT geom FROM {}.{}", schema, table_name))?
+pub fn qualified_name(table_name: &str, schema_name: &Option<String>) -> String {
+    if let Some(schema) = schema_name {
+        format!("{}.{}", quote_ident(schema), quote_ident(table_name))
     } else {
-        client.prepare(&format!("SELECT geom FROM {}", table_name))?
-    };
-    Ok(stmt)
+        quote_ident(table_name)
+    }
+}
+
+// Name a table is loaded under before being swapped in place of the existing one
+pub fn load_table_name(table_name: &str) -> String {
+    format!("{}_popgis_load", table_name)
 }
 
-pub fn create_table(
+pub fn create_schema<C: GenericClient>(client: &mut C, schema_name: &str) -> Result<()> {
+    client.batch_execute(&format!(
+        "CREATE SCHEMA IF NOT EXISTS {}",
+        quote_ident(schema_name)
+    ))?;
+    Ok(())
+}
+
+fn column_definitions(config: &[NewTableTypes], geometry_type: &str, srid: i32) -> String {
+    let mut columns = String::new();
+    for column in config.iter() {
+        let data_type = match column.data_type {
+            Type::INT4 => "INT",
+            Type::INT8 => "BIGINT",
+            Type::FLOAT8 => "DOUBLE PRECISION",
+            Type::TEXT => "TEXT",
+            Type::BOOL => "BOOL",
+            Type::TEXT_ARRAY => "TEXT[]",
+            Type::JSONB => "JSONB",
+            _ if column.data_type.name() == "hstore" => "hstore",
+            _ => {
+                println!("❌ Type currently not supported");
+                continue;
+            }
+        };
+        columns.push_str(&format!(
+            "{} {},",
+            quote_ident(&column.column_name),
+            data_type
+        ));
+    }
+    columns.push_str(&format!("geom Geometry({}, {})", geometry_type, srid));
+    columns
+}
+
+pub fn create_table<C: GenericClient>(
+    client: &mut C,
     table_name: &str,
     schema_name: &Option<String>,
     config: &[NewTableTypes],
-    uri: &str,
+    geometry_type: &str,
     srid: i32,
 ) -> Result<()> {
-    let mut query = String::from("CREATE TABLE IF NOT EXISTS ");
-    if let Some(schema) = schema_name {
-        query.push_str(&format!("{}.{} ", schema, table_name));
-    } else {
-        query.push_str(table_name);
-    }
-    query.push('(');
-    for column in config.iter() {
-        match column.data_type {
-            Type::INT8 => {
-                query.push_str(&format!("{} INT,", column.column_name));
-            }
-            Type::FLOAT8 => {
-                query.push_str(&format!("{} DOUBLE PRECISION,", column.column_name));
-            }
-            Type::TEXT => {
-                query.push_str(&format!("{} TEXT,", column.column_name));
-            }
-            Type::BOOL => {
-                query.push_str(&format!("{} BOOL,", column.column_name));
-            }
-            Type::TEXT_ARRAY => {
-                query.push_str(&format!("{} TEXT[],", column.column_name));
-            }
-            _ => println!("❌ Type currently not supported"),
-        }
-    }
-    query.push_str(&format!("geom Geometry(Geometry, {})", srid));
-    query.push_str(");");
+    let query = format!(
+        "CREATE TABLE IF NOT EXISTS {} ({});",
+        qualified_name(table_name, schema_name),
+        column_definitions(config, geometry_type, srid)
+    );
 
     // Debugging
     if cfg!(debug_assertions) {
         println!("DEBUG || {}", query);
     }
 
-    // If schema, println with schema
-    if let Some(schema) = schema_name {
-        println!("\n✅ Schema '{}' created", schema);
-        println!("✅ Table '{}' created", table_name);
-    } else {
-        println!("✅ Table '{}' created", table_name);
-    }
-
-    let mut client = create_connection(uri)?;
     client.execute(&query, &[])?;
 
     Ok(())
 }
 
-pub fn check_table_exists(table_name: &str, schema_name: &Option<String>, uri: &str) -> Result<()> {
-    let mut client = create_connection(uri)?;
-    let query = if let Some(schema) = schema_name {
-        format!(
-            "SELECT EXISTS (SELECT FROM information_schema.tables WHERE table_schema = '{}' AND table_name = '{}')",
-            schema, table_name
-        )
-    } else {
-        format!(
-            "SELECT EXISTS (SELECT FROM information_schema.tables WHERE table_name = '{}')",
-            table_name
-        )
+// Temporary table holding features of any geometry type, dropped when the transaction commits
+pub fn create_staging_table<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    config: &[NewTableTypes],
+    srid: i32,
+) -> Result<()> {
+    let query = format!(
+        "CREATE TEMP TABLE {} ({}) ON COMMIT DROP",
+        quote_ident(table_name),
+        column_definitions(config, "Geometry", srid)
+    );
+    client.execute(&query, &[])?;
+    Ok(())
+}
+
+// Copy the features of a staging table whose geometry fits the geometry type of a table
+// into it. Single geometries are promoted when the table holds multi geometries
+pub fn insert_from_staging<C: GenericClient>(
+    client: &mut C,
+    staging_table: &str,
+    table_name: &str,
+    schema_name: &Option<String>,
+    config: &[NewTableTypes],
+    geometry_type: &str,
+) -> Result<u64> {
+    let (filter, geom) = match geometry_type.strip_prefix("Multi") {
+        Some(single) => (
+            format!(
+                "GeometryType(geom) IN ('{}', '{}')",
+                single.to_uppercase(),
+                geometry_type.to_uppercase()
+            ),
+            "ST_Multi(geom)",
+        ),
+        None => (
+            format!("GeometryType(geom) = '{}'", geometry_type.to_uppercase()),
+            "geom",
+        ),
     };
-    let exists: bool = client.query_one(&query, &[])?.get(0);
+    let mut columns = String::new();
+    for column in config.iter() {
+        columns.push_str(&format!("{},", quote_ident(&column.column_name)));
+    }
+    let query = format!(
+        "INSERT INTO {} ({}geom) SELECT {}{} FROM {} WHERE {}",
+        qualified_name(table_name, schema_name),
+        columns,
+        columns,
+        geom,
+        quote_ident(staging_table),
+        filter
+    );
+    let count = client.execute(&query, &[])?;
+    Ok(count)
+}
+
+pub fn create_extension<C: GenericClient>(client: &mut C, extension: &str) -> Result<()> {
+    client.batch_execute(&format!(
+        "CREATE EXTENSION IF NOT EXISTS {}",
+        quote_ident(extension)
+    ))?;
+    Ok(())
+}
+
+pub fn check_table_exists<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+) -> Result<()> {
     // If exists, throw error
-    if exists {
+    if table_exists(client, table_name, schema_name)? {
         Err(Error::TableExists("❌ Table already exists".into()))
     } else {
         Ok(())
     }
 }
 
-pub fn drop_table(table_name: &str, schema_name: &Option<String>, uri: &str) -> Result<()> {
-    let mut client = create_connection(uri)?;
-    let query = if let Some(schema) = schema_name {
-        format!("DROP TABLE IF EXISTS {}.{}", schema, table_name)
+pub fn table_exists<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+) -> Result<bool> {
+    // Without a schema the table is looked up where it would be created
+    let query = "SELECT EXISTS (SELECT FROM information_schema.tables \
+                 WHERE table_schema = coalesce($1::TEXT, current_schema()) AND table_name = $2::TEXT)";
+    let exists: bool = client.query_one(query, &[schema_name, &table_name])?.get(0);
+    Ok(exists)
+}
+
+pub fn drop_table<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+) -> Result<()> {
+    let query = format!(
+        "DROP TABLE IF EXISTS {}",
+        qualified_name(table_name, schema_name)
+    );
+    client.execute(&query, &[])?;
+    Ok(())
+}
+
+pub fn rename_table<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    new_name: &str,
+) -> Result<()> {
+    let query = format!(
+        "ALTER TABLE {} RENAME TO {}",
+        qualified_name(table_name, schema_name),
+        quote_ident(new_name)
+    );
+    client.execute(&query, &[])?;
+    Ok(())
+}
+
+pub fn create_spatial_index<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+) -> Result<()> {
+    let query = format!(
+        "CREATE INDEX IF NOT EXISTS {} ON {} USING GIST (geom)",
+        quote_ident(&format!("{}_geom_idx", table_name)),
+        qualified_name(table_name, schema_name)
+    );
+    client.execute(&query, &[])?;
+    println!("✅ Spatial index created");
+    Ok(())
+}
+
+pub fn create_index<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    column: &str,
+) -> Result<()> {
+    let query = format!(
+        "CREATE INDEX IF NOT EXISTS {} ON {} ({})",
+        quote_ident(&format!("{}_{}_idx", table_name, column)),
+        qualified_name(table_name, schema_name),
+        quote_ident(column)
+    );
+    client.execute(&query, &[])?;
+    println!("✅ Index on '{}' created", column);
+    Ok(())
+}
+
+// Physically reorder the table following its spatial index
+pub fn cluster_table<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+) -> Result<()> {
+    let query = format!(
+        "CLUSTER {} USING {}",
+        qualified_name(table_name, schema_name),
+        quote_ident(&format!("{}_geom_idx", table_name))
+    );
+    client.execute(&query, &[])?;
+    println!("✅ Table clustered on spatial index");
+    Ok(())
+}
+
+pub fn analyze_table<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+) -> Result<()> {
+    let query = format!("ANALYZE {}", qualified_name(table_name, schema_name));
+    client.execute(&query, &[])?;
+    println!("✅ Table analyzed");
+    Ok(())
+}
+
+// Geometry column of the source or of a table. The type carries the Z/M suffix, as in
+// PointZ, and columns declared without a type modifier have no dimensions
+#[derive(Debug, Clone)]
+pub struct GeometryColumn {
+    pub geometry_type: String,
+    pub dims: Option<i32>,
+    pub srid: i32,
+}
+
+impl GeometryColumn {
+    pub fn new(geometry_type: &str, srid: i32) -> Self {
+        let (_, suffix) = split_dims(geometry_type);
+        GeometryColumn {
+            geometry_type: geometry_type.to_string(),
+            dims: Some(2 + suffix.len() as i32),
+            srid,
+        }
+    }
+}
+
+// Split a geometry type into the type and its Z/M suffix
+fn split_dims(geometry_type: &str) -> (&str, &str) {
+    for suffix in ["ZM", "Z", "M"] {
+        if let Some(base) = geometry_type.strip_suffix(suffix) {
+            return (base, suffix);
+        }
+    }
+    (geometry_type, "")
+}
+
+pub fn get_table_columns<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+) -> Result<(Vec<NewTableTypes>, GeometryColumn)> {
+    let table = qualified_name(table_name, schema_name);
+
+    let mut columns: Vec<NewTableTypes> = Vec::new();
+    let mut geometry: Option<GeometryColumn> = None;
+    let query = "SELECT a.attname::TEXT, a.atttypid, t.typname::TEXT, a.atttypmod, \
+                 t.typnamespace::regnamespace::TEXT \
+                 FROM pg_attribute a JOIN pg_type t ON t.oid = a.atttypid \
+                 WHERE a.attrelid = $1::TEXT::regclass AND a.attnum > 0 AND NOT a.attisdropped \
+                 ORDER BY a.attnum";
+    for row in client.query(query, &[&table])? {
+        let column_name: String = row.get(0);
+        let type_name: String = row.get(2);
+        if type_name == "geometry" {
+            if column_name == "geom" {
+                let typmod: i32 = row.get(3);
+                let stmt = "SELECT postgis_typmod_type($1), postgis_typmod_dims($1), \
+                            postgis_typmod_srid($1)";
+                let row = client.query_one(stmt, &[&typmod])?;
+                geometry = Some(GeometryColumn {
+                    geometry_type: row.get(0),
+                    dims: row.get(1),
+                    srid: row.get(2),
+                });
+            }
+            continue;
+        }
+        // Types added by extensions, like hstore, are only known by name
+        let oid = row.get(1);
+        let data_type = Type::from_oid(oid)
+            .unwrap_or_else(|| Type::new(type_name, oid, Kind::Simple, row.get(4)));
+        columns.push(NewTableTypes {
+            column_name,
+            data_type,
+        });
+    }
+
+    let geometry = geometry.ok_or(Error::IncompatibleSchema(
+        "❌ Table has no geom column".into(),
+    ))?;
+    Ok((columns, geometry))
+}
+
+// Match source columns to those of an existing table by name. Returns the columns to copy,
+// typed as in the table, and the position of each of them in the source rows
+pub fn match_columns(
+    source: &[NewTableTypes],
+    source_geometry: &GeometryColumn,
+    target: &[NewTableTypes],
+    target_geometry: &GeometryColumn,
+) -> Result<(Vec<NewTableTypes>, Vec<usize>)> {
+    let mut diff: Vec<String> = Vec::new();
+    // An SRID of 0 means the table accepts any SRID
+    if target_geometry.srid != 0 && source_geometry.srid != target_geometry.srid {
+        diff.push(format!(
+            "  geom: SRID {} in source, {} in table",
+            source_geometry.srid, target_geometry.srid
+        ));
+    }
+    // A Geometry column accepts any type, and one without dimensions any dimensions. Sources
+    // of mixed types, e.g. shapefiles of polygons and multipolygons, are checked row by row
+    // by the copy
+    let (source_type, source_suffix) = split_dims(&source_geometry.geometry_type);
+    let (target_type, target_suffix) = split_dims(&target_geometry.geometry_type);
+    let any_type = target_type == "Geometry" || source_type == "Geometry";
+    if !any_type && source_type != target_type {
+        diff.push(format!(
+            "  geom: {} in source, {} in table",
+            source_type, target_type
+        ));
+    }
+    if let Some(target_dims) = target_geometry.dims {
+        if source_suffix != target_suffix {
+            diff.push(format!(
+                "  geom: {} dimensions ({}) in source, {} ({}) in table",
+                source_geometry.dims.unwrap_or_default(),
+                source_geometry.geometry_type,
+                target_dims,
+                target_geometry.geometry_type
+            ));
+        }
+    }
+
+    let mut columns: Vec<NewTableTypes> = Vec::new();
+    let mut indices: Vec<usize> = Vec::new();
+    for (index, column) in source.iter().enumerate() {
+        match target.iter().find(|t| t.column_name == column.column_name) {
+            Some(t) if can_coerce(&column.data_type, &t.data_type) => {
+                columns.push(NewTableTypes {
+                    column_name: t.column_name.clone(),
+                    data_type: t.data_type.clone(),
+                });
+                indices.push(index);
+            }
+            Some(t) => diff.push(format!(
+                "  {}: {} in source, {} in table",
+                column.column_name,
+                column.data_type.name(),
+                t.data_type.name()
+            )),
+            None => println!(
+                "❌ Column '{}' not found in table, skipped",
+                column.column_name
+            ),
+        }
+    }
+
+    if !diff.is_empty() {
+        return Err(Error::IncompatibleSchema(format!(
+            "❌ Source is not compatible with the table:\n{}",
+            diff.join("\n")
+        )));
+    }
+    Ok((columns, indices))
+}
+
+// Map the columns of a query to the types they are exported as, and find its geometry column
+pub fn describe_query(client: &mut Client, query: &str) -> Result<(Vec<NewTableTypes>, String)> {
+    let stmt = client.prepare(&format!("SELECT * FROM ({}) AS popgis LIMIT 0", query))?;
+    let mut config: Vec<NewTableTypes> = Vec::new();
+    let mut geom_column: Option<String> = None;
+    for column in stmt.columns() {
+        let data_type = match *column.type_() {
+            Type::INT2 | Type::INT4 => Type::INT4,
+            Type::INT8 => Type::INT8,
+            Type::FLOAT4 | Type::FLOAT8 | Type::NUMERIC => Type::FLOAT8,
+            Type::BOOL => Type::BOOL,
+            Type::TEXT_ARRAY | Type::VARCHAR_ARRAY => Type::TEXT_ARRAY,
+            _ if column.type_().name() == "geometry" => {
+                // Only the first geometry column is exported
+                if geom_column.is_none() {
+                    geom_column = Some(column.name().to_string());
+                }
+                continue;
+            }
+            // Anything else is exported as its text representation
+            _ => Type::TEXT,
+        };
+        config.push(NewTableTypes {
+            column_name: column.name().to_string(),
+            data_type,
+        });
+    }
+
+    let geom_column = geom_column.ok_or(Error::FailedValidation(
+        "❌ No geometry column found to export".into(),
+    ))?;
+    Ok((config, geom_column))
+}
+
+// Returns the WKT of the geometry column's spatial reference, if it has one
+pub fn get_srtext(client: &mut Client, query: &str, geom_column: &str) -> Result<Option<String>> {
+    let query = format!(
+        "SELECT srtext::TEXT FROM spatial_ref_sys WHERE srid = (SELECT ST_SRID({geom}) FROM ({query}) AS popgis WHERE {geom} IS NOT NULL LIMIT 1)",
+        geom = quote_ident(geom_column),
+        query = query,
+    );
+    let srtext = client.query_opt(&query, &[])?.and_then(|row| row.get(0));
+    Ok(srtext)
+}
+
+// Entry of spatial_ref_sys describing a SRID
+pub struct SpatialRef {
+    pub auth_name: Option<String>,
+    pub auth_srid: Option<i32>,
+    pub srtext: Option<String>,
+    pub proj4text: Option<String>,
+}
+
+impl SpatialRef {
+    // Ways PROJ may know the CRS by, the authority code first
+    pub fn definitions(&self) -> Vec<String> {
+        let mut definitions = Vec::new();
+        if let (Some(auth_name), Some(auth_srid)) = (&self.auth_name, self.auth_srid) {
+            definitions.push(format!("{}:{}", auth_name, auth_srid));
+        }
+        for text in [&self.srtext, &self.proj4text].into_iter().flatten() {
+            if !text.trim().is_empty() {
+                definitions.push(text.trim().to_string());
+            }
+        }
+        definitions
+    }
+
+    pub fn defines(&self, definition: &str) -> bool {
+        [&self.srtext, &self.proj4text]
+            .into_iter()
+            .flatten()
+            .any(|text| text.trim() == definition.trim())
+    }
+}
+
+pub fn get_spatial_ref<C: GenericClient>(client: &mut C, srid: i32) -> Result<Option<SpatialRef>> {
+    let row = client.query_opt(
+        "SELECT auth_name::TEXT, auth_srid, srtext::TEXT, proj4text::TEXT FROM spatial_ref_sys WHERE srid = $1",
+        &[&srid],
+    )?;
+    Ok(row.map(|row| SpatialRef {
+        auth_name: row.get(0),
+        auth_srid: row.get(1),
+        srtext: row.get(2),
+        proj4text: row.get(3),
+    }))
+}
+
+// Add a CRS defined by a PROJ string or WKT under a SRID of our choosing
+pub fn insert_spatial_ref<C: GenericClient>(
+    client: &mut C,
+    srid: i32,
+    definition: &str,
+) -> Result<()> {
+    let (srtext, proj4text) = if definition.trim_start().starts_with('+') {
+        ("", definition)
     } else {
-        format!("DROP TABLE IF EXISTS {}", table_name)
+        (definition, "")
     };
-    client.execute(&query, &[])?;
+    client.execute(
+        "INSERT INTO spatial_ref_sys (srid, auth_name, auth_srid, srtext, proj4text) VALUES ($1, NULL, NULL, $2, $3)",
+        &[&srid, &srtext, &proj4text],
+    )?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(column_name: &str, data_type: Type) -> NewTableTypes {
+        NewTableTypes {
+            column_name: column_name.to_string(),
+            data_type,
+        }
+    }
+
+    #[test]
+    fn test_qualified_name() {
+        assert_eq!(qualified_name("Parcels", &None), "\"Parcels\"");
+        assert_eq!(
+            qualified_name("my \"table\"", &Some("order".to_string())),
+            "\"order\".\"my \"\"table\"\"\""
+        );
+    }
+
+    #[test]
+    fn test_match_columns() {
+        let source = vec![
+            column("name", Type::TEXT),
+            column("extra", Type::TEXT),
+            column("population", Type::INT4),
+        ];
+        let target = vec![
+            column("population", Type::INT8),
+            column("id", Type::INT4),
+            column("name", Type::TEXT),
+        ];
+        let geometry = GeometryColumn::new("Point", 4326);
+        let (columns, indices) = match_columns(&source, &geometry, &target, &geometry).unwrap();
+        assert_eq!(indices, vec![0, 2]);
+        assert_eq!(columns[0].column_name, "name");
+        assert_eq!(columns[1].data_type, Type::INT8);
+    }
+
+    #[test]
+    fn test_match_columns_incompatible() {
+        let source = vec![column("name", Type::TEXT)];
+        let target = vec![column("name", Type::INT4)];
+        let geometry = GeometryColumn::new("Point", 4326);
+        assert!(match_columns(&source, &geometry, &target, &geometry).is_err());
+        let table = GeometryColumn::new("Point", 3857);
+        assert!(match_columns(&source, &geometry, &[], &table).is_err());
+        let table = GeometryColumn::new("Point", 0);
+        assert!(match_columns(&source, &geometry, &[], &table).is_ok());
+    }
+
+    #[test]
+    fn test_match_columns_geometry() {
+        let source = vec![column("name", Type::TEXT)];
+        let point = GeometryColumn::new("Point", 4326);
+        let point_z = GeometryColumn::new("PointZ", 4326);
+        let point_m = GeometryColumn::new("PointM", 4326);
+        let line = GeometryColumn::new("LineString", 4326);
+        let any = GeometryColumn::new("Geometry", 4326);
+        // Columns declared as plain geometry, without type modifier
+        let unconstrained = GeometryColumn {
+            geometry_type: "Geometry".into(),
+            dims: None,
+            srid: 0,
+        };
+        assert!(match_columns(&source, &point, &[], &any).is_ok());
+        assert!(match_columns(&source, &any, &[], &point).is_ok());
+        assert!(match_columns(&source, &point_z, &[], &unconstrained).is_ok());
+        assert!(match_columns(&source, &point, &[], &line).is_err());
+        assert!(match_columns(&source, &point_z, &[], &point).is_err());
+        assert!(match_columns(&source, &point_z, &[], &point_m).is_err());
+        assert!(match_columns(&source, &point_z, &[], &any).is_err());
+        let Err(Error::IncompatibleSchema(message)) = match_columns(&source, &point_z, &[], &line)
+        else {
+            panic!("expected an incompatible schema");
+        };
+        assert!(message.contains("Point in source, LineString in table"));
+        assert!(message.contains("3 dimensions (PointZ) in source, 2 (LineString) in table"));
+    }
+}
@@ -1,2 +1,4 @@
 pub mod binary_copy;
 pub mod crud;
+pub mod osmchange;
+pub mod upsert;
@@ -0,0 +1,245 @@
+use crate::Result;
+use postgres::binary_copy::BinaryCopyInWriter;
+use postgres::types::Type;
+use postgres::GenericClient;
+use std::collections::HashMap;
+
+use crate::pg::crud::{drop_table, qualified_name, quote_ident};
+
+// Table holding the nodes of every way imported, so ways can be rebuilt when their
+// nodes move. Named after the import, e.g. `monaco_ways`
+pub fn ways_table_name(table_name: &str) -> String {
+    format!("{}_ways", table_name)
+}
+
+// Table holding the ways of every area relation imported, so relations can be rebuilt
+// when their ways change, e.g. `monaco_relations`
+pub fn relations_table_name(table_name: &str) -> String {
+    format!("{}_relations", table_name)
+}
+
+// Rebuilt on every import, like the node cache file it goes with
+pub fn create_ways_table<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+) -> Result<()> {
+    create_members_table(client, table_name, schema_name, "nodes")
+}
+
+pub fn create_relations_table<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+) -> Result<()> {
+    create_members_table(client, table_name, schema_name, "ways")
+}
+
+// Ways and relations are stored the same way, their id along with the ids of their members
+fn create_members_table<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    column: &str,
+) -> Result<()> {
+    drop_table(client, table_name, schema_name)?;
+    let query = format!(
+        "CREATE TABLE {} (id BIGINT PRIMARY KEY, {} BIGINT[] NOT NULL)",
+        qualified_name(table_name, schema_name),
+        column
+    );
+    client.execute(&query, &[])?;
+    Ok(())
+}
+
+pub fn copy_way_nodes<I, C>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    ways: I,
+) -> Result<u64>
+where
+    I: IntoIterator<Item = Result<(i64, Vec<i64>)>>,
+    C: GenericClient,
+{
+    copy_members(client, table_name, schema_name, "nodes", ways)
+}
+
+pub fn copy_relation_ways<I, C>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    relations: I,
+) -> Result<u64>
+where
+    I: IntoIterator<Item = Result<(i64, Vec<i64>)>>,
+    C: GenericClient,
+{
+    copy_members(client, table_name, schema_name, "ways", relations)
+}
+
+// Elements are looked up by their members, which needs an index on the array
+fn copy_members<I, C>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    column: &str,
+    elements: I,
+) -> Result<u64>
+where
+    I: IntoIterator<Item = Result<(i64, Vec<i64>)>>,
+    C: GenericClient,
+{
+    let table = qualified_name(table_name, schema_name);
+    let query = format!("COPY {} (id, {}) FROM stdin BINARY", table, column);
+    let writer = client.copy_in(&query)?;
+    let mut writer = BinaryCopyInWriter::new(writer, &[Type::INT8, Type::INT8_ARRAY]);
+    let mut count = 0;
+    for element in elements {
+        let (id, members) = element?;
+        writer.write(&[&id, &members])?;
+        count += 1;
+    }
+    writer.finish()?;
+
+    let query = format!(
+        "CREATE INDEX IF NOT EXISTS {} ON {} USING GIN ({})",
+        quote_ident(&format!("{}_{}_idx", table_name, column)),
+        table,
+        column
+    );
+    client.execute(&query, &[])?;
+    Ok(count)
+}
+
+pub fn upsert_way_nodes<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    ways: &HashMap<i64, Vec<i64>>,
+) -> Result<()> {
+    upsert_members(client, table_name, schema_name, "nodes", ways)
+}
+
+pub fn upsert_relation_ways<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    relations: &HashMap<i64, Vec<i64>>,
+) -> Result<()> {
+    upsert_members(client, table_name, schema_name, "ways", relations)
+}
+
+fn upsert_members<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    column: &str,
+    elements: &HashMap<i64, Vec<i64>>,
+) -> Result<()> {
+    let query = format!(
+        "INSERT INTO {} (id, {column}) VALUES ($1, $2) ON CONFLICT (id) DO UPDATE SET {column} = EXCLUDED.{column}",
+        qualified_name(table_name, schema_name)
+    );
+    let statement = client.prepare(&query)?;
+    for (id, members) in elements.iter() {
+        client.execute(&statement, &[id, members])?;
+    }
+    Ok(())
+}
+
+// Works for ways and relations alike
+pub fn delete_members<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    ids: &[i64],
+) -> Result<u64> {
+    let query = format!(
+        "DELETE FROM {} WHERE id = ANY($1)",
+        qualified_name(table_name, schema_name)
+    );
+    Ok(client.execute(&query, &[&ids])?)
+}
+
+// Nodes of the given ways
+pub fn get_way_nodes<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    ids: &[i64],
+) -> Result<HashMap<i64, Vec<i64>>> {
+    let query = format!(
+        "SELECT id, nodes FROM {} WHERE id = ANY($1)",
+        qualified_name(table_name, schema_name)
+    );
+    let rows = client.query(&query, &[&ids])?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+// Ways made of any of the given nodes
+pub fn ways_using_nodes<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    node_ids: &[i64],
+) -> Result<HashMap<i64, Vec<i64>>> {
+    elements_using(client, table_name, schema_name, "nodes", node_ids)
+}
+
+// Relations made of any of the given ways
+pub fn relations_using_ways<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    way_ids: &[i64],
+) -> Result<HashMap<i64, Vec<i64>>> {
+    elements_using(client, table_name, schema_name, "ways", way_ids)
+}
+
+fn elements_using<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    column: &str,
+    member_ids: &[i64],
+) -> Result<HashMap<i64, Vec<i64>>> {
+    let query = format!(
+        "SELECT id, {column} FROM {} WHERE {column} && $1",
+        qualified_name(table_name, schema_name)
+    );
+    let rows = client.query(&query, &[&member_ids])?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+// Features of a layer table coming from the given elements
+pub fn delete_features<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    osm_type: &str,
+    ids: &[i64],
+) -> Result<u64> {
+    let query = format!(
+        "DELETE FROM {} WHERE osm_type = $1 AND osm_id = ANY($2)",
+        qualified_name(table_name, schema_name)
+    );
+    Ok(client.execute(&query, &[&osm_type, &ids])?)
+}
+
+// Replace the geometry of a way or relation in a layer table, in the SRID of the table
+pub fn update_geometry<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    osm_type: &str,
+    id: i64,
+    wkb: &[u8],
+    srid: i32,
+) -> Result<u64> {
+    let query = format!(
+        "UPDATE {} SET geom = ST_GeomFromWKB($1, $2) WHERE osm_type = $3 AND osm_id = $4",
+        qualified_name(table_name, schema_name),
+    );
+    Ok(client.execute(&query, &[&wkb, &srid, &osm_type, &id])?)
+}
@@ -0,0 +1,148 @@
+use crate::{Error, Result};
+use postgres::GenericClient;
+
+use crate::format::common::{NewTableTypes, Row};
+use crate::pg::binary_copy::insert_rows;
+use crate::pg::crud::{qualified_name, quote_ident};
+
+// Temporary table rows are copied into before being merged into the target
+const STAGING_TABLE: &str = "popgis_staging";
+
+pub fn create_unique_index<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    key: &str,
+) -> Result<()> {
+    let query = format!(
+        "CREATE UNIQUE INDEX IF NOT EXISTS {} ON {} ({})",
+        quote_ident(&format!("{}_{}_key", table_name, key)),
+        qualified_name(table_name, schema_name),
+        quote_ident(key)
+    );
+    client.execute(&query, &[])?;
+    Ok(())
+}
+
+// ON CONFLICT needs a unique index or constraint on exactly the key column
+pub fn check_unique_key<C: GenericClient>(
+    client: &mut C,
+    table_name: &str,
+    schema_name: &Option<String>,
+    key: &str,
+) -> Result<()> {
+    let query = "SELECT EXISTS (SELECT FROM pg_index i \
+                 JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = i.indkey[0] \
+                 WHERE i.indrelid = $1::TEXT::regclass AND i.indisunique AND i.indnatts = 1 \
+                 AND i.indpred IS NULL AND a.attname = $2)";
+    let table = qualified_name(table_name, schema_name);
+    let exists: bool = client.query_one(query, &[&table, &key])?.get(0);
+    if exists {
+        Ok(())
+    } else {
+        Err(Error::Mode(format!(
+            "❌ Column '{}' needs a unique index or constraint to upsert on",
+            key
+        )))
+    }
+}
+
+// Copy rows into a staging table, then insert new features and update changed ones in
+// the target. Optionally delete features that are not in the source anymore. Meant to
+// run inside a transaction, the staging table is dropped when it commits
+#[allow(clippy::too_many_arguments)]
+pub fn upsert_rows<I, C>(
+    rows: I,
+    config: &[NewTableTypes],
+    client: &mut C,
+    schema_name: &Option<String>,
+    table_name: &str,
+    key: &str,
+    delete_missing: bool,
+) -> Result<()>
+where
+    I: IntoIterator<Item = Result<Row>>,
+    C: GenericClient,
+{
+    if !config.iter().any(|column| column.column_name == key) {
+        return Err(Error::Mode(format!(
+            "❌ Key column '{}' not found in source",
+            key
+        )));
+    }
+    let table = qualified_name(table_name, schema_name);
+    let quoted_key = quote_ident(key);
+
+    client.batch_execute(&format!(
+        "CREATE TEMP TABLE {} (LIKE {} INCLUDING DEFAULTS) ON COMMIT DROP",
+        STAGING_TABLE, table
+    ))?;
+    insert_rows(rows, config, client, &None, STAGING_TABLE)?;
+
+    // A key appearing twice would make ON CONFLICT update the same row twice
+    let query = format!(
+        "SELECT {}::TEXT FROM {} GROUP BY {} HAVING count(*) > 1 LIMIT 1",
+        quoted_key, STAGING_TABLE, quoted_key
+    );
+    if let Some(row) = client.query_opt(&query, &[])? {
+        let duplicate: Option<String> = row.get(0);
+        return Err(Error::Mode(format!(
+            "❌ Key '{}' appears more than once in source",
+            duplicate.unwrap_or("NULL".into())
+        )));
+    }
+
+    let mut columns: Vec<String> = config.iter().map(|c| quote_ident(&c.column_name)).collect();
+    columns.push("geom".into());
+    let updates: Vec<String> = columns
+        .iter()
+        .filter(|column| **column != quoted_key)
+        .map(|column| format!("{} = EXCLUDED.{}", column, column))
+        .collect();
+    let changed: Vec<String> = columns
+        .iter()
+        .filter(|column| **column != quoted_key)
+        .map(|column| format!("t.{} IS DISTINCT FROM EXCLUDED.{}", column, column))
+        .collect();
+    let columns = columns.join(", ");
+
+    // Unchanged features are left alone, xmax is 0 for freshly inserted rows
+    let query = format!(
+        "WITH upserted AS (\
+         INSERT INTO {table} AS t ({columns}) SELECT {columns} FROM {staging} \
+         ON CONFLICT ({key}) DO UPDATE SET {updates} WHERE {changed} \
+         RETURNING (xmax = 0) AS inserted) \
+         SELECT count(*) FILTER (WHERE inserted), count(*) FILTER (WHERE NOT inserted) FROM upserted",
+        table = table,
+        columns = columns,
+        staging = STAGING_TABLE,
+        key = quoted_key,
+        updates = updates.join(", "),
+        changed = if changed.is_empty() {
+            "false".to_string()
+        } else {
+            changed.join(" OR ")
+        },
+    );
+    let row = client.query_one(&query, &[])?;
+    let inserted: i64 = row.get(0);
+    let updated: i64 = row.get(1);
+
+    let deleted = if delete_missing {
+        let query = format!(
+            "DELETE FROM {} t WHERE NOT EXISTS (SELECT FROM {} s WHERE s.{} = t.{})",
+            table, STAGING_TABLE, quoted_key, quoted_key
+        );
+        client.execute(&query, &[])?
+    } else {
+        0
+    };
+
+    println!("✅ {} rows inserted", inserted);
+    println!("✅ {} rows updated", updated);
+    if delete_missing {
+        println!("✅ {} rows deleted", deleted);
+    }
+
+    Ok(())
+}
@@ -1,18 +1,50 @@
-use crate::format::common::{determine_file_type, FileType};
+use crate::format::common::{
+    determine_file_type, print_schema, sanitize_column_names, FileType, NewTableTypes,
+    OnMixedTypes, Row,
+};
+use crate::format::osmpbf::{routing, NodeCache, OsmProfile, TagStorage};
 use crate::format::shapefile;
-use crate::format::{geojson, osmpbf};
-use crate::pg::binary_copy::{infer_geom_type, insert_rows};
-use crate::pg::crud::{check_table_exists, create_schema, create_table, drop_table, get_stmt};
-use crate::utils::validate::validate_args;
+use crate::format::{geojson, geoparquet, osmpbf};
+use crate::pg::binary_copy::{copy_out_rows, insert_rows};
+use crate::pg::crud::{
+    analyze_table, check_table_exists, cluster_table, create_connection, create_extension,
+    create_index, create_schema, create_spatial_index, create_staging_table, create_table,
+    describe_query, drop_table, get_spatial_ref, get_srtext, get_table_columns,
+    insert_from_staging, insert_spatial_ref, load_table_name, match_columns, qualified_name,
+    rename_table, table_exists, GeometryColumn,
+};
+use crate::pg::osmchange::{
+    copy_relation_ways, copy_way_nodes, create_relations_table, create_ways_table,
+    relations_table_name, ways_table_name,
+};
+use crate::pg::upsert::{check_unique_key, create_unique_index, upsert_rows};
+use crate::utils::crs;
+use crate::utils::validate::{validate_args, validate_export_args};
 use crate::{Error, Result};
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
+use postgres::GenericClient;
 
-/// A blazing fast way to insert GeoJSON, ShapeFiles & OsmPBF into a PostGIS database
+/// A blazing fast way to insert GeoJSON, ShapeFiles, OsmPBF & GeoParquet into a PostGIS database
 #[derive(Parser, Debug)]
-#[command(about, version)]
+#[command(
+    about,
+    version,
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
+)]
+struct Popgis {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // Import arguments, required unless a subcommand is given
+    #[command(flatten)]
+    import: Option<Cli>,
+}
+
+#[derive(Args, Debug, Clone, Default)]
 pub struct Cli {
-    /// Input file path, either shapefile or geojson
+    /// Input file path, either shapefile, geojson, osm.pbf, geoparquet or osc
     #[arg(short, long)]
     pub input: String,
 
@@ -28,57 +60,587 @@ pub struct Cli {
     #[arg(short, long)]
     pub schema: Option<String>,
 
-    /// Srid, if not provided, will default to 4326
+    /// Srid, any in spatial_ref_sys. Optional, read from the .prj or GeoParquet metadata, or 4326
     #[arg(long)]
     pub srid: Option<i32>,
 
-    /// Mode: overwrite or fail. Optional.
+    /// PROJ string or WKT of the srid, added to spatial_ref_sys when missing. Optional.
+    #[arg(long, requires = "srid")]
+    pub srid_definition: Option<String>,
+
+    /// Mode: overwrite, append, upsert, apply or fail. Optional.
     #[arg(short, long, default_value = "fail")]
     pub mode: Option<String>,
 
-    /// Reproject: reproject to 4326 or 3857. Optional.
+    /// Key column features are matched on in upsert mode
+    #[arg(short, long)]
+    pub key: Option<String>,
+
+    /// Delete rows whose key is not in the input, in upsert mode
+    #[arg(long)]
+    pub delete_missing: bool,
+
+    /// Number of features scanned to infer column types. Optional, all of them by default.
+    #[arg(long)]
+    pub sample: Option<usize>,
+
+    /// What to do with a column holding values of different types
+    #[arg(long, value_enum, default_value_t = OnMixedTypes::Text)]
+    pub on_mixed_types: OnMixedTypes,
+
+    /// How OsmPbf tags are stored: array of key=value, jsonb or hstore
+    #[arg(long, value_enum, default_value_t = TagStorage::Array)]
+    pub tags: TagStorage,
+
+    /// OsmPbf tags to store in columns of their own, comma separated. Optional.
+    #[arg(long, value_delimiter = ',')]
+    pub columns: Vec<String>,
+
+    /// OsmPbf features to keep, comma separated, e.g. highway=*,amenity=restaurant|cafe,!building
+    #[arg(long, value_delimiter = ',')]
+    pub osm_filter: Vec<String>,
+
+    /// Keys making a closed OsmPbf way a polygon, comma separated. Optional, replaces the defaults.
+    #[arg(long, value_delimiter = ',')]
+    pub area_keys: Vec<String>,
+
+    /// Keys keeping a closed OsmPbf way a line, comma separated. Optional, replaces the defaults.
+    #[arg(long, value_delimiter = ',')]
+    pub linear_keys: Vec<String>,
+
+    /// Where OsmPbf node locations are kept: sorted vector, dense arrays or a memory-mapped file
+    #[arg(long, value_enum, default_value_t = NodeCache::Sparse)]
+    pub node_cache: NodeCache,
+
+    /// File backing the node cache in file mode. Optional, a temporary file by default.
+    #[arg(long)]
+    pub node_cache_file: Option<String>,
+
+    /// What an OsmPbf import produces: tables of features, or a road network for pgRouting
+    #[arg(long, value_enum, default_value_t = OsmProfile::Features)]
+    pub profile: OsmProfile,
+
+    /// Add the version, timestamp, changeset, uid and user of OsmPbf elements as columns
+    #[arg(long)]
+    pub osm_metadata: bool,
+
+    /// Threads decoding OsmPbf blocks or converting features. Optional, all cores by default.
+    #[arg(long)]
+    pub threads: Option<usize>,
+
+    /// Store single part Shapefile lines and polygons as multi geometries, like multipart ones
+    #[arg(long)]
+    pub promote_to_multi: bool,
+
+    /// Lowercase column names and replace characters that would need quoting in SQL
+    #[arg(long)]
+    pub sanitize_names: bool,
+
+    /// Skip creating a spatial index on the geometry column
+    #[arg(long)]
+    pub no_spatial_index: bool,
+
+    /// Columns to create a B-tree index on, comma separated. Optional.
+    #[arg(long, value_delimiter = ',')]
+    pub index: Vec<String>,
+
+    /// Cluster the table on its spatial index after loading
+    #[arg(long)]
+    pub cluster: bool,
+
+    /// Skip running ANALYZE after loading
+    #[arg(long)]
+    pub no_analyze: bool,
+
+    /// Reproject: reproject to any srid in spatial_ref_sys. Optional.
     #[arg(short, long)]
     pub reproject: Option<i32>,
+
+    /// PROJ string or WKT of the reproject srid, added to spatial_ref_sys when missing. Optional.
+    #[arg(long, requires = "reproject")]
+    pub reproject_definition: Option<String>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Export a PostGIS table or query to GeoJSON or ShapeFile
+    Export(ExportCli),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ExportCli {
+    /// Output file path, either geojson or shapefile
+    #[arg(short, long)]
+    pub output: String,
+
+    /// PostgreSQL URI
+    #[arg(short, long)]
+    pub uri: String,
+
+    /// Table name to export. Required unless a query is given.
+    #[arg(short, long, required_unless_present = "query")]
+    pub table: Option<String>,
+
+    /// Schema name of the table. Optional.
+    #[arg(short, long)]
+    pub schema: Option<String>,
+
+    /// SQL query to export instead of a table. Optional.
+    #[arg(short, long, conflicts_with_all = ["table", "schema"])]
+    pub query: Option<String>,
 }
 
 pub fn run() -> Result<()> {
-    let mut args = Cli::parse();
+    let mut args = match Popgis::parse() {
+        // Export goes the other way round, from PostGIS to a file
+        Popgis {
+            command: Some(Command::Export(export_args)),
+            ..
+        } => return export(export_args),
+        Popgis {
+            import: Some(args), ..
+        } => args,
+        // Import arguments are required without a subcommand
+        Popgis { import: None, .. } => unreachable!(),
+    };
     validate_args(&args)?;
 
-    // If not provided srid will default to 4326
+    let file_type = determine_file_type(&args.input)?;
+    if file_type == FileType::Osmpbf && args.mode.as_deref() == Some("upsert") {
+        return Err(Error::Mode(
+            "❌ Upsert mode is not supported for OsmPbf".into(),
+        ));
+    }
+    if (file_type == FileType::OsmChange) != (args.mode.as_deref() == Some("apply")) {
+        return Err(Error::Mode(
+            "❌ Apply mode is only used, and always needed, with OsmChange files".into(),
+        ));
+    }
+
+    if file_type == FileType::Osmpbf && args.profile == OsmProfile::Routing {
+        args.srid = Some(4326);
+        return import_routing(&args);
+    }
+
+    // If not provided srid will be read from the GeoParquet metadata or the shapefile .prj,
+    // or default to 4326
     if args.srid.is_none() {
+        match file_type {
+            FileType::GeoParquet => args.srid = geoparquet::determine_srid(&args.input)?,
+            FileType::Shapefile => args.srid = shapefile::determine_srid(&args.input)?,
+            _ => {}
+        }
         args.srid.get_or_insert(4326);
     }
 
-    let file_type = determine_file_type(&args.input)?;
-    let (rows, config) = match file_type {
-        FileType::Shapefile => (
-            shapefile::read_shapefile(&args)?,
-            shapefile::determine_data_types(&args.input)?,
-        ),
-        FileType::GeoJson => (
-            geojson::read_geojson(&args)?,
-            geojson::determine_data_types(&args.input)?,
-        ),
-        FileType::Osmpbf => {
+    let config = match file_type {
+        FileType::Shapefile => {
+            shapefile::determine_data_types(&args.input, args.sample, args.on_mixed_types)?
+        }
+        FileType::GeoJson => {
+            geojson::determine_data_types(&args.input, args.sample, args.on_mixed_types)?
+        }
+        FileType::GeoParquet => geoparquet::determine_data_types(&args.input)?,
+        FileType::Osmpbf | FileType::OsmChange => {
             args.srid = Some(4326); // OsmPbf files are always in 4326
-            (osmpbf::read_osmpbf(&args)?, osmpbf::determine_data_types()?)
+            osmpbf::determine_data_types(args.tags, &args.columns, args.osm_metadata)?
+        }
+    };
+
+    // Changes are read along with the tables they apply to
+    if file_type == FileType::OsmChange {
+        return apply_osm_changes(args, config);
+    }
+
+    // Everything runs in one transaction, a failure leaves the database untouched
+    let mut client = create_connection(&args.uri)?;
+    let mut transaction = client.transaction()?;
+
+    // Find how PROJ knows the CRS before any reader needs it
+    resolve_crs(
+        &mut transaction,
+        args.srid.unwrap(),
+        &mut args.srid_definition,
+    )?;
+    if let Some(reproject) = args.reproject {
+        resolve_crs(&mut transaction, reproject, &mut args.reproject_definition)?;
+    }
+
+    // Rows are streamed straight from the reader into the binary copy, with values
+    // looked up by the names in the source
+    let rows = match file_type {
+        FileType::Shapefile => shapefile::read_shapefile(&args, &config)?,
+        FileType::GeoJson => geojson::read_geojson(&args, &config)?,
+        FileType::GeoParquet => geoparquet::read_geoparquet(&args, &config)?,
+        FileType::Osmpbf => osmpbf::read_osmpbf(&args)?,
+        FileType::OsmChange => unreachable!("OsmChange files are applied above"),
+    };
+
+    let config = if args.sanitize_names {
+        sanitize_column_names(config)
+    } else {
+        config
+    };
+    print_schema(&config);
+
+    // OsmPbf features are split into a table per geometry type, anything else goes in one
+    // Shapefiles and GeoParquet with Z or M values get a geometry column with those dimensions
+    let tables: Vec<(String, &str)> = match file_type {
+        FileType::Osmpbf => layer_tables(&args),
+        FileType::Shapefile => vec![(args.table.clone(), shapefile::geometry_type(&args.input)?)],
+        FileType::GeoParquet => {
+            vec![(args.table.clone(), geoparquet::geometry_type(&args.input)?)]
         }
+        _ => vec![(args.table.clone(), "Geometry")],
     };
 
+    if file_type == FileType::Osmpbf && args.tags == TagStorage::Hstore {
+        create_extension(&mut transaction, "hstore")?;
+    }
+
+    let mut targets: Vec<Target> = Vec::new();
+    for (table, geometry_type) in tables {
+        targets.push(prepare_table(
+            &mut transaction,
+            &args,
+            table,
+            geometry_type,
+            &config,
+        )?);
+    }
+
+    if let [target] = targets.as_slice() {
+        let rows = target_rows(rows, target, config.len());
+
+        if let (Some("upsert"), Some(key)) = (args.mode.as_deref(), &args.key) {
+            // A table created by popgis gets its key indexed, existing ones must have it already
+            if target.create {
+                create_unique_index(&mut transaction, &target.table, &args.schema, key)?;
+            }
+            check_unique_key(&mut transaction, &target.table, &args.schema, key)?;
+            upsert_rows(
+                rows,
+                &target.config,
+                &mut transaction,
+                &args.schema,
+                &target.table,
+                key,
+                args.delete_missing,
+            )?;
+        } else {
+            insert_rows(
+                rows,
+                &target.config,
+                &mut transaction,
+                &args.schema,
+                &target.load_table,
+            )?;
+        }
+    } else {
+        insert_layers(&mut transaction, &args, &config, rows, &targets)?;
+    }
+
+    for target in targets.iter() {
+        finish_table(&mut transaction, &args, target)?;
+    }
+
+    // Nodes of every way and ways of every area relation are kept along with the node cache,
+    // for changes to be applied later
+    if file_type == FileType::Osmpbf && args.node_cache_file.is_some() {
+        let ways_table = ways_table_name(&args.table);
+        create_ways_table(&mut transaction, &ways_table, &args.schema)?;
+        let count = copy_way_nodes(
+            &mut transaction,
+            &ways_table,
+            &args.schema,
+            osmpbf::read_way_nodes(&args)?,
+        )?;
+        println!("✅ {} ways stored in '{}'", count, ways_table);
+        let relations_table = relations_table_name(&args.table);
+        create_relations_table(&mut transaction, &relations_table, &args.schema)?;
+        let count = copy_relation_ways(
+            &mut transaction,
+            &relations_table,
+            &args.schema,
+            osmpbf::read_relation_ways(&args)?,
+        )?;
+        println!("✅ {} relations stored in '{}'", count, relations_table);
+    }
+
+    transaction.commit()?;
+
+    // If schema, println with schema
+    if let Some(schema) = &args.schema {
+        if targets.iter().any(|target| target.create) {
+            println!("✅ Schema '{}' created", schema);
+        }
+    }
+    for target in targets.iter().filter(|target| target.create) {
+        println!("✅ Table '{}' created", target.table);
+    }
+
+    Ok(())
+}
+
+// Check a SRID against spatial_ref_sys and PROJ, and set the definition readers give PROJ.
+// A custom definition is added to spatial_ref_sys when it isn't there yet
+fn resolve_crs<C: GenericClient>(
+    client: &mut C,
+    srid: i32,
+    definition: &mut Option<String>,
+) -> Result<()> {
+    let spatial_ref = get_spatial_ref(client, srid)?;
+    if let Some(custom) = definition.as_deref() {
+        if !crs::is_known(custom) {
+            return Err(Error::FailedValidation(format!(
+                "❌ PROJ can't read the definition of SRID {}",
+                srid
+            )));
+        }
+        match spatial_ref {
+            None => {
+                insert_spatial_ref(client, srid, custom)?;
+                println!("✅ SRID {} added to spatial_ref_sys", srid);
+            }
+            Some(spatial_ref) if spatial_ref.defines(custom) => {}
+            Some(_) => {
+                return Err(Error::FailedValidation(format!(
+                    "❌ SRID {} is already in spatial_ref_sys with another definition",
+                    srid
+                )))
+            }
+        }
+        return Ok(());
+    }
+
+    let Some(spatial_ref) = spatial_ref else {
+        return Err(Error::FailedValidation(format!(
+            "❌ SRID {} not found in spatial_ref_sys, give its definition to add it",
+            srid
+        )));
+    };
+    // The authority code first, the WKT or PROJ string otherwise
+    match spatial_ref
+        .definitions()
+        .into_iter()
+        .find(|candidate| crs::is_known(candidate))
+    {
+        Some(known) => {
+            *definition = Some(known);
+            Ok(())
+        }
+        None => Err(Error::FailedValidation(format!(
+            "❌ SRID {} is not known to PROJ",
+            srid
+        ))),
+    }
+}
+
+// Temporary table OsmPbf features are copied into before being split by geometry type
+const LAYERS_STAGING_TABLE: &str = "popgis_layers";
+
+// Values of the columns a target has, geometry is always the last value of a row
+fn target_rows<I>(rows: I, target: &Target, source_len: usize) -> impl Iterator<Item = Result<Row>>
+where
+    I: IntoIterator<Item = Result<Row>>,
+{
+    let mut indices = target.indices.clone();
+    indices.push(source_len);
+    rows.into_iter()
+        .map(move |row| row.map(|row| row.select(&indices)))
+}
+
+// Build a road network from OsmPbf highways, into edges and vertices tables for pgRouting
+fn import_routing(args: &Cli) -> Result<()> {
+    let graph = routing::build_graph(args)?;
+    let configs = [routing::edge_data_types(), routing::vertex_data_types()];
+    for config in configs.iter() {
+        print_schema(config);
+    }
+
+    let mut client = create_connection(&args.uri)?;
+    let mut transaction = client.transaction()?;
+
+    let mut targets: Vec<Target> = Vec::new();
+    for ((suffix, geometry_type), config) in routing::ROUTING_LAYERS.iter().zip(configs.iter()) {
+        targets.push(prepare_table(
+            &mut transaction,
+            args,
+            format!("{}_{}", args.table, suffix),
+            geometry_type,
+            config,
+        )?);
+    }
+    let [edges, vertices] = targets.as_slice() else {
+        unreachable!();
+    };
+
+    // Vertices first, the graph then goes to the edges reader
+    insert_rows(
+        target_rows(graph.vertex_rows(), vertices, configs[1].len()),
+        &vertices.config,
+        &mut transaction,
+        &args.schema,
+        &vertices.load_table,
+    )?;
+    insert_rows(
+        target_rows(routing::read_edges(args, graph)?, edges, configs[0].len()),
+        &edges.config,
+        &mut transaction,
+        &args.schema,
+        &edges.load_table,
+    )?;
+
+    for target in targets.iter() {
+        finish_table(&mut transaction, args, target)?;
+    }
+    // Routing looks edges up by the vertices they join
+    for column in ["source", "target"] {
+        create_index(&mut transaction, &edges.table, &args.schema, column)?;
+    }
+
+    transaction.commit()?;
+
+    for target in targets.iter().filter(|target| target.create) {
+        println!("✅ Table '{}' created", target.table);
+    }
+
+    Ok(())
+}
+
+// OsmPbf tables named after the import, with the geometry type they hold
+fn layer_tables(args: &Cli) -> Vec<(String, &'static str)> {
+    osmpbf::LAYERS
+        .iter()
+        .map(|(suffix, geometry_type)| (format!("{}_{}", args.table, suffix), *geometry_type))
+        .collect()
+}
+
+// A single COPY into a staging table, then each table takes the features matching its
+// geometry type
+fn insert_layers<I, C>(
+    client: &mut C,
+    args: &Cli,
+    config: &[NewTableTypes],
+    rows: I,
+    targets: &[Target],
+) -> Result<()>
+where
+    I: IntoIterator<Item = Result<Row>>,
+    C: GenericClient,
+{
+    let srid = args.reproject.or(args.srid).unwrap();
+    create_staging_table(client, LAYERS_STAGING_TABLE, config, srid)?;
+    insert_rows(rows, config, client, &None, LAYERS_STAGING_TABLE)?;
+    for target in targets.iter() {
+        let count = insert_from_staging(
+            client,
+            LAYERS_STAGING_TABLE,
+            &target.load_table,
+            &args.schema,
+            &target.config,
+            target.geometry_type,
+        )?;
+        println!("✅ {} rows inserted into '{}'", count, target.table);
+    }
+    Ok(())
+}
+
+// Apply an OsmChange file to the tables and node cache of an earlier OsmPbf import
+fn apply_osm_changes(mut args: Cli, config: Vec<NewTableTypes>) -> Result<()> {
+    let config = if args.sanitize_names {
+        sanitize_column_names(config)
+    } else {
+        config
+    };
+    let node_cache_file = args.node_cache_file.as_deref().unwrap();
+    let mut store = osmpbf::open_node_store(node_cache_file)?;
+
+    let mut client = create_connection(&args.uri)?;
+    let mut transaction = client.transaction()?;
+
+    // Changes are reprojected like the import was
+    if let Some(reproject) = args.reproject {
+        resolve_crs(&mut transaction, reproject, &mut args.reproject_definition)?;
+    }
+
+    let mut targets: Vec<Target> = Vec::new();
+    for (table, geometry_type) in layer_tables(&args) {
+        targets.push(prepare_table(
+            &mut transaction,
+            &args,
+            table,
+            geometry_type,
+            &config,
+        )?);
+    }
+    let (rows, node_changes) = osmpbf::apply_changes(&mut transaction, &args, store.as_ref())?;
+    insert_layers(
+        &mut transaction,
+        &args,
+        &config,
+        rows.into_iter().map(Ok),
+        &targets,
+    )?;
+    if !args.no_analyze {
+        for target in targets.iter() {
+            analyze_table(&mut transaction, &target.table, &args.schema)?;
+        }
+    }
+
+    transaction.commit()?;
+
+    // The node cache only moves on once the tables have
+    node_changes.apply(store.as_mut())?;
+    println!("✅ Changes applied to '{}'", args.table);
+
+    Ok(())
+}
+
+// A table written by the import
+struct Target {
+    table: String,
+    // Table rows are copied into, only differs from table when replacing it
+    load_table: String,
+    geometry_type: &'static str,
+    create: bool,
+    swap: bool,
+    // Columns written, typed as in the table, and their position in the source rows
+    config: Vec<NewTableTypes>,
+    indices: Vec<usize>,
+}
+
+// Check the table against the mode, create it if needed and match the source columns to it
+fn prepare_table<C: GenericClient>(
+    client: &mut C,
+    args: &Cli,
+    table: String,
+    geometry_type: &'static str,
+    config: &[NewTableTypes],
+) -> Result<Target> {
     // If mode not present, check if table exists
-    let create = if args.mode.is_none() {
-        check_table_exists(&args.table, &args.schema, &args.uri)?;
-        true
+    let (create, swap) = if args.mode.is_none() {
+        check_table_exists(client, &table, &args.schema)?;
+        (true, false)
     } else if let Some(mode) = &args.mode {
         match mode.as_str() {
-            "overwrite" => {
-                drop_table(&args.table, &args.schema, &args.uri)?;
-                true
-            }
+            // The existing table is only replaced once the new one is fully loaded
+            "overwrite" => (true, true),
             "fail" => {
-                check_table_exists(&args.table, &args.schema, &args.uri)?;
-                true
+                check_table_exists(client, &table, &args.schema)?;
+                (true, false)
+            }
+            // Append and upsert create the table only if it isn't there yet
+            "append" | "upsert" => (!table_exists(client, &table, &args.schema)?, false),
+            // Changes apply to tables imported before
+            "apply" => {
+                if !table_exists(client, &table, &args.schema)? {
+                    return Err(Error::Mode(format!(
+                        "❌ Table '{}' not found, changes apply to an earlier import",
+                        table
+                    )));
+                }
+                (false, false)
             }
             _ => {
                 println!("Mode not supported ✘");
@@ -86,38 +648,163 @@ pub fn run() -> Result<()> {
             }
         }
     } else {
-        false
+        (false, false)
+    };
+    let load_table = if swap {
+        load_table_name(&table)
+    } else {
+        table.clone()
     };
 
+    let srid = args.reproject.or(args.srid).unwrap();
     if create {
         // If schema present, create schema
         if let Some(schema) = &args.schema {
-            create_schema(schema, &args.uri)?;
-        }
-        //
-        if let Some(reproject) = args.reproject {
-            create_table(&args.table, &args.schema, &config, &args.uri, reproject)?
-        } else if args.reproject.is_none() {
-            create_table(
-                &args.table,
-                &args.schema,
-                &config,
-                &args.uri,
-                args.srid.unwrap(),
-            )?
+            create_schema(client, schema)?;
+        }
+        if swap {
+            drop_table(client, &load_table, &args.schema)?;
         }
+        create_table(
+            client,
+            &load_table,
+            &args.schema,
+            config,
+            geometry_type,
+            srid,
+        )?;
     }
 
-    let stmt = get_stmt(&args.table, &args.schema, &args.uri)?;
-    let geom_type = infer_geom_type(stmt)?;
-    insert_rows(
-        &rows,
-        &config,
-        geom_type,
-        &args.uri,
-        &args.schema,
-        &args.table,
-    )?;
+    // When appending to an existing table, match source columns to the table's by name
+    let (config, indices) = if create {
+        (config.to_vec(), (0..config.len()).collect())
+    } else {
+        let (target, target_geometry) = get_table_columns(client, &table, &args.schema)?;
+        let source_geometry = GeometryColumn::new(geometry_type, srid);
+        match_columns(config, &source_geometry, &target, &target_geometry)?
+    };
+
+    Ok(Target {
+        table,
+        load_table,
+        geometry_type,
+        create,
+        swap,
+        config,
+        indices,
+    })
+}
+
+// Swap the loaded table in place and build its indexes
+fn finish_table<C: GenericClient>(client: &mut C, args: &Cli, target: &Target) -> Result<()> {
+    // Readers keep using the old table until the transaction commits
+    if target.swap {
+        drop_table(client, &target.table, &args.schema)?;
+        rename_table(client, &target.load_table, &args.schema, &target.table)?;
+    }
+
+    // Indexes are built after the copy so the load itself stays fast
+    if !args.no_spatial_index {
+        create_spatial_index(client, &target.table, &args.schema)?;
+    }
+    for column in args.index.iter() {
+        create_index(client, &target.table, &args.schema, column)?;
+    }
+    if args.cluster {
+        cluster_table(client, &target.table, &args.schema)?;
+    }
+    if !args.no_analyze {
+        analyze_table(client, &target.table, &args.schema)?;
+    }
+    Ok(())
+}
+
+fn export(args: ExportCli) -> Result<()> {
+    validate_export_args(&args)?;
+
+    let query = match (&args.query, &args.table, &args.schema) {
+        (Some(query), _, _) => query.clone(),
+        (None, Some(table), schema) => format!("SELECT * FROM {}", qualified_name(table, schema)),
+        (None, None, _) => return Err(Error::FailedValidation("❌ Table is empty".into())),
+    };
+
+    let mut client = create_connection(&args.uri)?;
+    let (config, geom_column) = describe_query(&mut client, &query)?;
+
+    println!("Exporting data from database...");
+
+    let count = match determine_file_type(&args.output)? {
+        FileType::GeoJson => {
+            let rows = copy_out_rows(&mut client, &query, &config, &geom_column)?;
+            geojson::write_geojson(&args.output, &config, rows)?
+        }
+        FileType::Shapefile => {
+            let srtext = get_srtext(&mut client, &query, &geom_column)?;
+            let rows = copy_out_rows(&mut client, &query, &config, &geom_column)?;
+            shapefile::write_shapefile(&args.output, &config, rows, srtext)?
+        }
+        _ => {
+            return Err(Error::UnsupportedFileExtension(
+                "❌ Export supports geojson and shapefile only".into(),
+            ))
+        }
+    };
+
+    println!("🎉 {} rows sucessfully exported to {}", count, args.output);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_export() {
+        let args = Popgis::try_parse_from([
+            "popgis",
+            "export",
+            "-o",
+            "out.geojson",
+            "-u",
+            "postgresql://",
+            "-t",
+            "waters",
+        ])
+        .unwrap();
+        let Some(Command::Export(export_args)) = args.command else {
+            panic!("expected the export subcommand");
+        };
+        assert_eq!(export_args.output, "out.geojson");
+        assert_eq!(export_args.table.as_deref(), Some("waters"));
+
+        // Imports still need their arguments, and can't be mixed with an export
+        assert!(Popgis::try_parse_from(["popgis"]).is_err());
+        assert!(Popgis::try_parse_from(["popgis", "-i", "in.geojson"]).is_err());
+        assert!(Popgis::try_parse_from([
+            "popgis",
+            "-i",
+            "in.geojson",
+            "export",
+            "-o",
+            "out.geojson",
+            "-u",
+            "postgresql://",
+            "-t",
+            "waters",
+        ])
+        .is_err());
+        let args = Popgis::try_parse_from([
+            "popgis",
+            "-i",
+            "in.geojson",
+            "-u",
+            "postgresql://",
+            "-t",
+            "waters",
+        ])
+        .unwrap();
+        assert!(args.command.is_none());
+        assert_eq!(args.import.unwrap().input, "in.geojson");
+    }
+}
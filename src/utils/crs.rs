@@ -0,0 +1,130 @@
+use crate::utils::cli::Cli;
+use crate::Result;
+
+use proj::Proj;
+use proj_sys::{
+    proj_context_create, proj_context_destroy, proj_create, proj_destroy, proj_get_id_code,
+    proj_identify, proj_int_list_destroy, proj_list_destroy, proj_list_get, proj_list_get_count,
+};
+use std::ffi::{c_int, CStr, CString};
+use std::ptr;
+
+// Confidence out of 100 PROJ needs in a match for it to be used. Ellipsoid or axis
+// differences lower it, a match on the name alone gives 70
+const MIN_CONFIDENCE: c_int = 70;
+
+// Whether PROJ can read a CRS definition, an authority code, a PROJ string or WKT
+pub fn is_known(definition: &str) -> bool {
+    Proj::new(definition).is_ok()
+}
+
+// CRS to reproject from and to, as PROJ definitions. Those of SRIDs without a definition
+// are taken to be EPSG codes
+pub fn reprojection(args: &Cli) -> Option<(String, String)> {
+    let definition = |srid: Option<i32>, definition: &Option<String>| {
+        definition
+            .clone()
+            .or_else(|| srid.map(|srid| format!("EPSG:{}", srid)))
+    };
+    Some((
+        definition(args.srid, &args.srid_definition)?,
+        definition(args.reproject, &args.reproject_definition)?,
+    ))
+}
+
+// Transformer of the reprojection, if any. PROJ objects can't be shared between threads,
+// each one builds its own
+pub fn transformer(args: &Cli) -> Result<Option<Proj>> {
+    match reprojection(args) {
+        Some((from, to)) => Ok(Some(Proj::new_known_crs(&from, &to, None)?)),
+        None => Ok(None),
+    }
+}
+
+// EPSG code of a CRS in WKT, as declared by the CRS itself or else as identified by PROJ
+pub fn wkt_to_srid(wkt: &str) -> Option<i32> {
+    wkt_authority(wkt).or_else(|| identify_epsg(wkt))
+}
+
+// Code of the EPSG AUTHORITY, or ID in WKT2, of the root of a WKT. Those nested deeper
+// belong to its parts, e.g. the datum or the base geographic CRS
+fn wkt_authority(wkt: &str) -> Option<i32> {
+    let mut depth = 0;
+    let mut quoted = false;
+    for (index, char) in wkt.char_indices() {
+        match char {
+            '"' => quoted = !quoted,
+            '[' | '(' if !quoted => depth += 1,
+            ']' | ')' if !quoted => depth -= 1,
+            ',' if !quoted && depth == 1 => {
+                let rest = wkt[index + 1..].trim_start();
+                let Some(values) = ["AUTHORITY[", "ID["]
+                    .iter()
+                    .find_map(|keyword| rest.strip_prefix(keyword))
+                else {
+                    continue;
+                };
+                let mut values = values
+                    .split(']')
+                    .next()?
+                    .split(',')
+                    .map(|value| value.trim().trim_matches('"'));
+                if values.next()?.eq_ignore_ascii_case("EPSG") {
+                    return values.next()?.parse().ok();
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// Best EPSG match PROJ finds for a CRS, e.g. for the ESRI WKT of most .prj files
+fn identify_epsg(wkt: &str) -> Option<i32> {
+    let definition = CString::new(wkt).ok()?;
+    let authority = CString::new("EPSG").ok()?;
+    let mut srid = None;
+    unsafe {
+        let ctx = proj_context_create();
+        let crs = proj_create(ctx, definition.as_ptr());
+        if !crs.is_null() {
+            let mut confidence: *mut c_int = ptr::null_mut();
+            let matches = proj_identify(ctx, crs, authority.as_ptr(), ptr::null(), &mut confidence);
+            if !matches.is_null() {
+                // Matches are sorted by decreasing confidence
+                if proj_list_get_count(matches) > 0 && *confidence >= MIN_CONFIDENCE {
+                    let candidate = proj_list_get(ctx, matches, 0);
+                    let code = proj_get_id_code(candidate, 0);
+                    if !code.is_null() {
+                        srid = CStr::from_ptr(code)
+                            .to_str()
+                            .ok()
+                            .and_then(|code| code.parse().ok());
+                    }
+                    proj_destroy(candidate);
+                }
+                proj_int_list_destroy(confidence);
+                proj_list_destroy(matches);
+            }
+            proj_destroy(crs);
+        }
+        proj_context_destroy(ctx);
+    }
+    srid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wkt_authority() {
+        let wkt = r#"PROJCS["ETRS89 / UTM zone 30N",GEOGCS["ETRS89",DATUM["European_Terrestrial_Reference_System_1989",SPHEROID["GRS 1980",6378137,298.257222101,AUTHORITY["EPSG","7019"]],AUTHORITY["EPSG","6258"]],PRIMEM["Greenwich",0],UNIT["degree",0.0174532925199433],AUTHORITY["EPSG","4258"]],PROJECTION["Transverse_Mercator"],UNIT["metre",1],AUTHORITY["EPSG","25830"]]"#;
+        assert_eq!(wkt_authority(wkt), Some(25830));
+        let wkt = r#"GEOGCRS["WGS 84",DATUM["World Geodetic System 1984",ELLIPSOID["WGS 84",6378137,298.257223563]],ID["EPSG",4326]]"#;
+        assert_eq!(wkt_authority(wkt), Some(4326));
+        // Only the base CRS has an authority
+        let wkt = r#"PROJCS["Custom",GEOGCS["ETRS89",DATUM["ETRS89"],AUTHORITY["EPSG","4258"]],PROJECTION["Transverse_Mercator"]]"#;
+        assert_eq!(wkt_authority(wkt), None);
+    }
+}
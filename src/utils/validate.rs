@@ -1,4 +1,5 @@
-use super::cli::Cli;
+use super::cli::{Cli, ExportCli};
+use crate::format::osmpbf::{self, NodeCache, OsmFilter, OsmProfile};
 use crate::{Error, Result};
 use std::path::Path;
 
@@ -21,15 +22,112 @@ pub fn validate_args(args: &Cli) -> Result<()> {
         return Err(Error::FailedValidation("❌ Table is empty".into()));
     }
 
-    // Check if srid is 4326 or 3857
-    if let Some(srid) = args.srid {
-        if srid != 4326 && srid != 3857 {
+    // Check upsert has a key to match features on
+    let upsert = args.mode.as_deref() == Some("upsert");
+    if upsert && args.key.is_none() {
+        return Err(Error::FailedValidation(
+            "❌ Upsert mode requires a key column".into(),
+        ));
+    }
+    if !upsert && (args.key.is_some() || args.delete_missing) {
+        return Err(Error::FailedValidation(
+            "❌ Key and delete missing are only used in upsert mode".into(),
+        ));
+    }
+
+    // Check changes have the node cache of the import they apply to, and that a node
+    // cache file is only given where it is used
+    let apply = args.mode.as_deref() == Some("apply");
+    if apply && args.node_cache_file.is_none() {
+        return Err(Error::FailedValidation(
+            "❌ Apply mode requires the node cache file of the import".into(),
+        ));
+    }
+    if !apply && args.node_cache_file.is_some() && args.node_cache != NodeCache::File {
+        return Err(Error::FailedValidation(
+            "❌ A node cache file is only used with the file node cache".into(),
+        ));
+    }
+
+    // Check routing imports replace their tables, edge ids start over on every import, and
+    // stay in 4326, the CRS of OSM data
+    if args.profile == OsmProfile::Routing && (upsert || args.mode.as_deref() == Some("append")) {
+        return Err(Error::Mode(
+            "❌ Append and upsert modes are not supported by the routing profile".into(),
+        ));
+    }
+    if args.profile == OsmProfile::Routing && args.reproject.is_some() {
+        return Err(Error::FailedValidation(
+            "❌ Reproject is not supported by the routing profile".into(),
+        ));
+    }
+
+    // Check promoted tags don't clash with the columns every OsmPbf table has
+    if let Some(column) = args.columns.iter().find(|column| {
+        *column == "tags"
+            || *column == "geom"
+            || osmpbf::ID_COLUMNS.contains(&column.as_str())
+            || osmpbf::METADATA_COLUMNS.contains(&column.as_str())
+    }) {
+        return Err(Error::FailedValidation(format!(
+            "❌ '{}' can't be used as a tag column",
+            column
+        )));
+    }
+
+    // Check OsmPbf filter expressions can be parsed
+    OsmFilter::parse(&args.osm_filter)?;
+
+    // Check clustering has a spatial index to cluster on
+    if args.cluster && args.no_spatial_index {
+        return Err(Error::FailedValidation(
+            "❌ Cluster requires the spatial index".into(),
+        ));
+    }
+
+    // Check SRIDs are positive, and those of custom definitions within the range PostGIS
+    // leaves to users
+    for (srid, definition) in [
+        (args.srid, &args.srid_definition),
+        (args.reproject, &args.reproject_definition),
+    ] {
+        let max = if definition.is_some() {
+            998999
+        } else {
+            i32::MAX
+        };
+        if let Some(srid) = srid.filter(|srid| !(1..=max).contains(srid)) {
+            return Err(Error::FailedValidation(format!(
+                "❌ SRID {} must be between 1 and {}",
+                srid, max
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// Validate the export arguments
+pub fn validate_export_args(args: &ExportCli) -> Result<()> {
+    // Check output file is not empty
+    if args.output.is_empty() {
+        return Err(Error::FailedValidation("❌ Output is empty".into()));
+    }
+
+    // Check output directory exists
+    if let Some(parent) = Path::new(&args.output).parent() {
+        if !parent.as_os_str().is_empty() && !parent.exists() {
             return Err(Error::FailedValidation(
-                "❌ SRID must be 4326 or 3857".into(),
+                "❌ Output directory does not exist".into(),
             ));
         }
     }
 
+    // Check URL is not empty
+    if args.uri.is_empty() {
+        return Err(Error::FailedValidation("❌ URL is empty".into()));
+    }
+
     Ok(())
 }
 
@@ -48,6 +146,7 @@ mod tests {
             srid: None,
             mode: None,
             reproject: None,
+            ..Default::default()
         };
         assert!(validate_args(&args).is_ok());
     }
@@ -63,7 +162,126 @@ mod tests {
             srid: Some(4326),
             mode: None,
             reproject: None,
+            ..Default::default()
         };
         assert!(validate_args(&args).is_ok());
     }
+
+    #[test]
+    fn test_validate_args_srid() {
+        let args = Cli {
+            input: "examples/shapefile/andalucia.shp".to_string(),
+            uri: "postgresql://localhost:5432/postgis".to_string(),
+            table: "points".to_string(),
+            srid: Some(25830),
+            reproject: Some(102100),
+            ..Default::default()
+        };
+        assert!(validate_args(&args).is_ok());
+        let args = Cli {
+            srid: Some(0),
+            ..args
+        };
+        assert!(validate_args(&args).is_err());
+        // Custom definitions are added to spatial_ref_sys, which tops out at 998999
+        let args = Cli {
+            srid: Some(999000),
+            srid_definition: Some("+proj=longlat +datum=WGS84 +no_defs".to_string()),
+            ..args
+        };
+        assert!(validate_args(&args).is_err());
+    }
+
+    // Upsert without a key
+    #[test]
+    fn test_validate_args_upsert() {
+        let args = Cli {
+            input: "examples/geojson/spain.geojson".to_string(),
+            uri: "postgresql://localhost:5432/postgis".to_string(),
+            table: "points".to_string(),
+            mode: Some("upsert".to_string()),
+            ..Default::default()
+        };
+        assert!(validate_args(&args).is_err());
+        let args = Cli {
+            key: Some("id".to_string()),
+            ..args
+        };
+        assert!(validate_args(&args).is_ok());
+    }
+
+    #[test]
+    fn test_validate_args_apply() {
+        let args = Cli {
+            input: "examples/osmchange/monaco.osc".to_string(),
+            uri: "postgresql://localhost:5432/postgis".to_string(),
+            table: "monaco".to_string(),
+            mode: Some("apply".to_string()),
+            ..Default::default()
+        };
+        assert!(validate_args(&args).is_err());
+        let args = Cli {
+            node_cache_file: Some("monaco-nodes.bin".to_string()),
+            ..args
+        };
+        assert!(validate_args(&args).is_ok());
+        let args = Cli {
+            mode: Some("append".to_string()),
+            ..args
+        };
+        assert!(validate_args(&args).is_err());
+    }
+
+    #[test]
+    fn test_validate_args_routing() {
+        let args = Cli {
+            input: "examples/osmpbf/monaco-latest.osm.pbf".to_string(),
+            uri: "postgresql://localhost:5432/postgis".to_string(),
+            table: "monaco".to_string(),
+            profile: OsmProfile::Routing,
+            mode: Some("overwrite".to_string()),
+            ..Default::default()
+        };
+        assert!(validate_args(&args).is_ok());
+        let routing = Cli {
+            reproject: Some(3857),
+            ..args.clone()
+        };
+        assert!(validate_args(&routing).is_err());
+        let args = Cli {
+            mode: Some("append".to_string()),
+            ..args
+        };
+        assert!(validate_args(&args).is_err());
+    }
+
+    #[test]
+    fn test_validate_args_cluster() {
+        let args = Cli {
+            input: "examples/geojson/spain.geojson".to_string(),
+            uri: "postgresql://localhost:5432/postgis".to_string(),
+            table: "points".to_string(),
+            cluster: true,
+            no_spatial_index: true,
+            ..Default::default()
+        };
+        assert!(validate_args(&args).is_err());
+        let args = Cli {
+            no_spatial_index: false,
+            ..args
+        };
+        assert!(validate_args(&args).is_ok());
+    }
+
+    #[test]
+    fn test_validate_export_args() {
+        let args = ExportCli {
+            output: "missing/dir/points.geojson".to_string(),
+            uri: "postgresql://localhost:5432/postgis".to_string(),
+            table: Some("points".to_string()),
+            schema: None,
+            query: None,
+        };
+        assert!(validate_export_args(&args).is_err());
+    }
 }